@@ -0,0 +1,77 @@
+use anyhow::Result;
+use log::{debug, info};
+use skstack_rs::echonet_lite;
+use skstack_rs::skstack::{CoordinatorConfig, RouteBPassword, SKEvent, UdpHandle, SKSTACK};
+
+/// Instantaneous power reported to every request, in watts
+const CURRENT_POWER: i32 = 1234;
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let device_path = std::env::var("DEVICE_PATH")?;
+    let routeb_password: RouteBPassword = std::env::var("ROUTEB_PASSWORD")?.parse()?;
+
+    let mut skstack = SKSTACK::open(device_path, None)?;
+    let version = skstack.init()?;
+    println!("version: {}", version);
+    skstack.set_password(&routeb_password)?;
+    skstack.start_coordinator(&CoordinatorConfig {
+        channel: 33,
+        pan_id: 0x8888,
+        pairing_id: std::env::var("PAIRING_ID")
+            .ok()
+            .map(|id| id.parse())
+            .transpose()?,
+    })?;
+
+    let client = skstack.accept_join()?;
+    info!("joined: {}", client);
+
+    loop {
+        let (sender, rport, data) = match skstack.read_event()? {
+            SKEvent::ERXUDP {
+                sender,
+                rport,
+                lport: 3610,
+                data,
+                ..
+            } => (sender, rport, data),
+            _ => continue,
+        };
+        let request = echonet_lite::EFrame::from_bytes(&data).expect("read frame");
+        debug!("{:?}", request);
+        if let Some(response) = current_power_response(request) {
+            skstack.send_udp(UdpHandle::new(1)?, rport, &sender, &response.as_bytes())?;
+        }
+    }
+}
+
+fn current_power_response(request: echonet_lite::EFrame) -> Option<echonet_lite::EFrame> {
+    let (seoj, deoj) = match request.edata {
+        echonet_lite::EDATA::Format1 {
+            seoj,
+            deoj,
+            esv: echonet_lite::ESV::Get,
+            props,
+            ..
+        } if props.iter().any(|prop| prop.epc == 0xE7) => (seoj, deoj),
+        _ => return None,
+    };
+    Some(echonet_lite::EFrame {
+        ehd1: echonet_lite::ECHONET_LITE_HEADER1,
+        ehd2: echonet_lite::EHD2::Format1,
+        tid: request.tid,
+        edata: echonet_lite::EDATA::Format1 {
+            seoj: deoj,
+            deoj: seoj,
+            esv: echonet_lite::ESV::Get_Res,
+            opc: 1,
+            props: vec![echonet_lite::EProp {
+                // 瞬時電力計測値
+                epc: 0xE7,
+                pdc: 4,
+                edt: CURRENT_POWER.to_be_bytes().to_vec(),
+            }],
+        },
+    })
+}
@@ -1,17 +1,17 @@
+use std::net::Ipv6Addr;
 use std::time::Duration;
 
 use anyhow::Result;
-use echonet_lite::EFrame;
 use log::{debug, error, warn};
 use nix::unistd::sleep;
 use rand::{prelude::ThreadRng, Rng};
 use skstack_rs::echonet_lite;
-use skstack_rs::skstack::{SKEvent, SKPan, SKSTACK};
+use skstack_rs::skstack::{RouteBId, RouteBPassword, SKEvent, SKPan, UdpHandle, SKSTACK};
 
 const TARGET_EOJ: echonet_lite::EOJ = echonet_lite::EOJ {
-    /// 住宅・設備関連機器クラスグループ
+    // 住宅・設備関連機器クラスグループ
     x1: 0x02,
-    /// 低圧スマート電力量メータ
+    // 低圧スマート電力量メータ
     x2: 0x88,
     x3: 0x01,
 };
@@ -19,34 +19,30 @@ const TARGET_EOJ: echonet_lite::EOJ = echonet_lite::EOJ {
 fn main() -> Result<()> {
     env_logger::init();
     let device_path = std::env::var("DEVICE_PATH")?;
-    let routeb_password = std::env::var("ROUTEB_PASSWORD")?;
-    let routeb_id = std::env::var("ROUTEB_ID")?;
+    let routeb_password: RouteBPassword = std::env::var("ROUTEB_PASSWORD")?.parse()?;
+    let routeb_id: RouteBId = std::env::var("ROUTEB_ID")?.parse()?;
 
     let mut skstack = crate::SKSTACK::open(device_path, None)?;
-    let version = skstack.version()?;
+    // Optional file to record the serial traffic in to reproduce issues
+    if let Ok(path) = std::env::var("TRANSCRIPT_PATH") {
+        skstack.record_transcript(std::fs::File::create(path)?);
+    }
+    let version = skstack.init()?;
     println!("version: {}", version);
-    skstack.set_password(routeb_password)?;
-    skstack.set_rbid(routeb_id)?;
+    skstack.set_password(&routeb_password)?;
+    skstack.set_rbid(&routeb_id)?;
 
-    let mut duration = 4;
-    let mut found: Vec<SKPan>;
-    loop {
-        debug!("scanning (duration = {})", duration);
-        found = skstack.scan(2, 0xFFFFFFFF, duration)?;
-        if !found.is_empty() {
-            break;
-        }
-        duration += 1;
-        if duration > 15 {
-            panic!("duration too long: {}", duration);
-        }
+    // Optional file to cache the PAN descriptor in to skip scanning on restart
+    let pan_cache_path = std::env::var("PAN_CACHE_PATH").ok();
+    let cached: Option<SKPan> = pan_cache_path
+        .as_ref()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| text.parse().ok());
+    let (found, ip_v6_addr) = skstack.connect(cached.as_ref(), routeb_id.pairing_id())?;
+    debug!("joined PAN: {:?} (RSSI {:.1} dBm)", found, found.rssi());
+    if let Some(path) = pan_cache_path {
+        std::fs::write(path, found.to_string())?;
     }
-    let found = found.first().unwrap();
-    debug!("found PAN: {:?}", found);
-    skstack.set_register("S2", format!("{:X}", found.channel))?;
-    skstack.set_register("S3", format!("{:X}", found.pan_id))?;
-    let ip_v6_addr = skstack.get_link_local_addr(found.addr.clone())?;
-    skstack.join(&ip_v6_addr)?;
 
     skstack.set_timeout(Some(Duration::from_millis(10000)));
     let mut rng = rand::thread_rng();
@@ -61,14 +57,14 @@ fn main() -> Result<()> {
 
 fn send_echonet_request(
     frame_fn: fn(echonet_lite::TID) -> echonet_lite::EFrame,
-    ip_v6_addr: &str,
+    ip_v6_addr: &Ipv6Addr,
     skstack: &mut SKSTACK,
     rng: &mut ThreadRng,
 ) -> anyhow::Result<echonet_lite::EFrame> {
     'request_loop: loop {
         let tid = rng.gen();
         let frame = frame_fn(tid);
-        skstack.send_udp(1, 3610, &ip_v6_addr, &frame.as_bytes())?;
+        skstack.send_udp(UdpHandle::new(1)?, 3610, ip_v6_addr, &frame.as_bytes())?;
 
         loop {
             let event = match skstack.read_event() {
@@ -82,16 +78,13 @@ fn send_echonet_request(
                     return Err(error.into());
                 }
             };
-            match event {
-                SKEvent::ERXUDP { data, .. } => {
-                    let frame = echonet_lite::EFrame::from_bytes(&data).expect("read frame");
-                    debug!("{:?}", frame);
-                    if frame.tid != tid {
-                        continue;
-                    }
-                    return Ok(frame);
+            if let SKEvent::ERXUDP { data, .. } = event {
+                let frame = echonet_lite::EFrame::from_bytes(&data).expect("read frame");
+                debug!("{:?}", frame);
+                if frame.tid != tid {
+                    continue;
                 }
-                _ => {}
+                return Ok(frame);
             }
         }
     }
@@ -123,14 +116,14 @@ fn get_current_power_frame(tid: echonet_lite::TID) -> echonet_lite::EFrame {
     echonet_lite::EFrame {
         ehd1: echonet_lite::ECHONET_LITE_HEADER1,
         ehd2: echonet_lite::EHD2::Format1,
-        tid: tid,
+        tid,
         edata: echonet_lite::EDATA::Format1 {
             seoj: SELF_EOJ,
             deoj: TARGET_EOJ,
             esv: echonet_lite::ESV::Get,
             opc: 1,
             props: vec![echonet_lite::EProp {
-                /// 瞬時電力計測値
+                // 瞬時電力計測値
                 epc: 0xE7,
                 pdc: 0,
                 edt: vec![],
@@ -138,43 +131,3 @@ fn get_current_power_frame(tid: echonet_lite::TID) -> echonet_lite::EFrame {
         },
     }
 }
-
-fn get_integral_power_unit_frame(tid: echonet_lite::TID) -> echonet_lite::EFrame {
-    echonet_lite::EFrame {
-        ehd1: echonet_lite::ECHONET_LITE_HEADER1,
-        ehd2: echonet_lite::EHD2::Format1,
-        tid: tid,
-        edata: echonet_lite::EDATA::Format1 {
-            seoj: SELF_EOJ,
-            deoj: TARGET_EOJ,
-            esv: echonet_lite::ESV::Get,
-            opc: 1,
-            props: vec![echonet_lite::EProp {
-                /// 積算電力量単位（正方向、逆方向計測値）
-                epc: 0xE1,
-                pdc: 0,
-                edt: vec![],
-            }],
-        },
-    }
-}
-
-fn get_integral_power_frame(tid: echonet_lite::TID) -> echonet_lite::EFrame {
-    echonet_lite::EFrame {
-        ehd1: echonet_lite::ECHONET_LITE_HEADER1,
-        ehd2: echonet_lite::EHD2::Format1,
-        tid: tid,
-        edata: echonet_lite::EDATA::Format1 {
-            seoj: SELF_EOJ,
-            deoj: TARGET_EOJ,
-            esv: echonet_lite::ESV::Get,
-            opc: 1,
-            props: vec![echonet_lite::EProp {
-                /// 積算電力量計測値履歴１(正方向計測値)
-                epc: 0xE2,
-                pdc: 0,
-                edt: vec![],
-            }],
-        },
-    }
-}
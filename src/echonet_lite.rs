@@ -128,8 +128,7 @@ pub struct EFrame {
 impl EFrame {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
         let ehd2 = EHD2::try_from(bytes[1])?;
-        let edata: EDATA;
-        match ehd2 {
+        let edata = match ehd2 {
             EHD2::Format1 => {
                 let opc = bytes[11];
                 let mut props = vec![];
@@ -144,7 +143,7 @@ impl EFrame {
                     props.push(EProp { epc, pdc, edt });
                 }
 
-                edata = EDATA::Format1 {
+                EDATA::Format1 {
                     seoj: EOJ {
                         x1: bytes[4],
                         x2: bytes[5],
@@ -156,19 +155,17 @@ impl EFrame {
                         x3: bytes[9],
                     },
                     esv: ESV::try_from(bytes[10])?,
-                    opc: opc,
-                    props: props,
+                    opc,
+                    props,
                 }
             }
-            EHD2::Format2 => {
-                edata = EDATA::Format2(bytes[4..].into());
-            }
-        }
+            EHD2::Format2 => EDATA::Format2(bytes[4..].into()),
+        };
         Ok(Self {
             ehd1: bytes[0],
-            ehd2: ehd2,
+            ehd2,
             tid: TID::from_be_bytes([bytes[2], bytes[3]]),
-            edata: edata,
+            edata,
         })
     }
     pub fn as_bytes(&self) -> Vec<u8> {
@@ -1,3 +1,4 @@
-mod tty;
 pub mod echonet_lite;
-pub mod skstack;
\ No newline at end of file
+pub mod skstack;
+mod transport;
+mod tty;
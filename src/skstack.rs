@@ -1,13 +1,21 @@
 use core::fmt;
 use fmt::Debug;
-use log::info;
+use log::{debug, trace, warn};
 use memchr;
+use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
+use zeroize::Zeroize;
 
+use crate::transport::Port;
+pub use crate::transport::{Replayer, Transport};
 use crate::tty::{self, TTYPort};
+pub use crate::tty::{DataBits, DeviceSelector, FlowControl, Parity, SerialConfig, StopBits};
 use std::{
-    io::{BufRead, Write},
-    time::Duration,
-    usize,
+    collections::VecDeque,
+    convert::TryFrom,
+    fmt::Write as _,
+    io::{BufRead, Read, Write},
+    net::Ipv6Addr,
+    time::{Duration, Instant},
 };
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -18,8 +26,27 @@ pub enum Error {
     Io(std::io::Error),
     Decode(String),
     ParseInt(std::num::ParseIntError),
+    AddrParse(std::net::AddrParseError),
     UnexpectedEvent(SKEvent),
     ExpectOK(String),
+    /// Command rejected by the module with `FAIL ERxx`
+    Fail(u8),
+    InvalidArgument(String),
+    LinkDown(Ipv6Addr),
+    /// The device is already opened by another process
+    DeviceBusy(String),
+    /// The device hung up, e.g. after a USB unplug
+    Disconnected,
+    /// The device was disconnected while waiting for the reply to a command
+    /// and has been reopened at the given path by the reconnect policy. The
+    /// command has to be sent again, after joining again if it needs the
+    /// PANA session.
+    Reconnected(String),
+    /// A flash memory command (SKSAVE, SKLOAD or SKERASE) was rejected
+    Flash {
+        command: &'static str,
+        code: u8,
+    },
 }
 impl Error {
     pub fn is_timeout(&self) -> bool {
@@ -37,120 +64,1294 @@ impl fmt::Display for Error {
             Error::Io(error) => <std::io::Error as fmt::Display>::fmt(error, fmt),
             Error::Decode(string) => write!(fmt, "{}", string),
             Error::ParseInt(error) => <std::num::ParseIntError as fmt::Display>::fmt(error, fmt),
+            Error::AddrParse(error) => <std::net::AddrParseError as fmt::Display>::fmt(error, fmt),
             Error::UnexpectedEvent(error) => write!(fmt, "unexpected event: {:?}", error),
             Error::ExpectOK(string) => write!(fmt, "{}", string),
+            Error::Fail(code) => {
+                let reason = match code {
+                    4 => "unsupported command",
+                    5 => "invalid number of arguments",
+                    6 => "invalid argument format or out of range",
+                    9 => "UART input error",
+                    10 => "command failed to execute",
+                    _ => "unknown error",
+                };
+                write!(fmt, "FAIL ER{:02}: {}", code, reason)
+            }
+            Error::InvalidArgument(string) => write!(fmt, "invalid argument: {}", string),
+            Error::DeviceBusy(path) => write!(fmt, "device {} is busy", path),
+            Error::Disconnected => write!(fmt, "device disconnected"),
+            Error::Reconnected(path) => write!(fmt, "device reconnected at {}", path),
+            Error::Flash { command, code } => {
+                let reason = match (*command, code) {
+                    ("SKLOAD", 10) => "no saved settings in flash",
+                    (_, 10) => "flash memory access failed",
+                    _ => "command rejected",
+                };
+                write!(fmt, "{} failed with ER{:02}: {}", command, code, reason)
+            }
+            Error::LinkDown(addr) => write!(fmt, "link to {} is down", format_ipv6_addr(addr)),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        if tty::is_disconnected(&error) {
+            Error::Disconnected
+        } else {
+            Error::Io(error)
         }
     }
-}
+}
+
+impl From<tty::Error> for Error {
+    fn from(error: tty::Error) -> Self {
+        let error: std::io::Error = error.into();
+        error.into()
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(error: std::str::Utf8Error) -> Self {
+        Error::StrDecode(error)
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(error: std::num::ParseIntError) -> Self {
+        Error::ParseInt(error)
+    }
+}
+
+impl From<std::net::AddrParseError> for Error {
+    fn from(error: std::net::AddrParseError) -> Self {
+        Error::AddrParse(error)
+    }
+}
+
+impl<T: TryFromPrimitive> From<TryFromPrimitiveError<T>> for Error {
+    fn from(error: TryFromPrimitiveError<T>) -> Self {
+        Error::Decode(format!("{:?}", error))
+    }
+}
+
+pub struct SKSTACK {
+    reader: std::io::BufReader<Port>,
+    /// Used to find the device again after it is re-enumerated, `None` if
+    /// opened with a custom transport
+    selector: Option<DeviceSelector>,
+    config: SerialConfig,
+    reconnect_policy: Option<ReconnectPolicy>,
+    /// Whether the module echoes back each command line (register SFE)
+    echo_back: bool,
+    /// How the module displays the data field of ERXUDP (ROPT/WOPT)
+    erxudp_format: ERXUDPFormat,
+    keepalive: Option<Keepalive>,
+    /// Local port bound to each UDP handle, 0 if unused or unknown
+    udp_ports: [u16; UdpHandle::MAX as usize],
+    /// When the keepalive peer was last heard from
+    last_alive: Option<Instant>,
+    /// Handles of the established TCP connections
+    tcp_handles: Vec<u8>,
+    /// Events received while waiting for a reply, to be returned by
+    /// `read_event` first
+    pending_events: VecDeque<SKEvent>,
+    /// Credentials set on the module, which are lost when it is power
+    /// cycled and set again after reconnecting
+    password: Option<RouteBPassword>,
+    rbid: Option<RouteBId>,
+    psk: Option<Key128>,
+}
+
+/// Settings put back on the module after it is reopened
+#[derive(Clone, Debug)]
+struct RestoredSettings {
+    echo_back: bool,
+    erxudp_format: ERXUDPFormat,
+    udp_ports: [u16; UdpHandle::MAX as usize],
+}
+
+/// How [`SKSTACK::read_event`] reopens the device after it is disconnected
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Delay before each attempt to find and reopen the device
+    pub interval: Duration,
+    /// Number of attempts before giving up
+    pub max_attempts: u32,
+}
+
+/// PAN parameters used by [`SKSTACK::start_coordinator`]
+#[derive(Clone, Debug)]
+pub struct CoordinatorConfig {
+    /// Logical channel (33-60)
+    pub channel: u8,
+    pub pan_id: u16,
+    /// Pairing ID advertised in beacons, which devices filter scan results
+    /// by. Defaults to the module's own setting if `None`.
+    pub pairing_id: Option<PairingId>,
+}
+
+/// Keepalive settings used by [`SKSTACK::check_keepalive`]
+#[derive(Clone, Debug)]
+pub struct Keepalive {
+    /// Peer to ping, usually the PANA authentication agent
+    pub ip_v6_addr: Ipv6Addr,
+    /// How long the peer may stay silent before it is pinged again
+    pub interval: Duration,
+    /// How long to wait for EPONG
+    pub timeout: Duration,
+}
+
+/// Display format of the data field of ERXUDP events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ERXUDPFormat {
+    /// Raw bytes of `datalen` length
+    Binary = 0x00,
+    /// Hex-encoded ASCII string
+    Ascii = 0x01,
+}
+
+/// Formats an IPv6 address in the uncompressed notation used by SKSTACK,
+/// e.g. `FE80:0000:0000:0000:1207:23FF:FEA0:75B3`
+pub fn format_ipv6_addr(addr: &Ipv6Addr) -> String {
+    addr.segments()
+        .iter()
+        .map(|segment| format!("{:04X}", segment))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// 64-bit MAC address, written as 16 hex digits by SKSTACK
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddr64(pub [u8; 8]);
+
+impl MacAddr64 {
+    pub fn octets(&self) -> [u8; 8] {
+        self.0
+    }
+
+    /// Recovers the MAC address from an IPv6 link-local address derived by
+    /// [`MacAddr64::link_local_addr`]. Returns `None` if the address is not
+    /// in fe80::/64.
+    pub fn from_link_local_addr(addr: &Ipv6Addr) -> Option<Self> {
+        let octets = addr.octets();
+        if octets[..8] != [0xFE, 0x80, 0, 0, 0, 0, 0, 0] {
+            return None;
+        }
+        let mut mac = [0u8; 8];
+        mac.copy_from_slice(&octets[8..]);
+        mac[0] ^= 0x02;
+        Some(MacAddr64(mac))
+    }
+
+    /// Derives the IPv6 link-local address in the same way as SKLL64:
+    /// the address is used as the interface identifier with the
+    /// universal/local bit flipped, prefixed by fe80::/64.
+    pub fn link_local_addr(&self) -> Ipv6Addr {
+        let mut octets = [0u8; 16];
+        octets[0] = 0xFE;
+        octets[1] = 0x80;
+        octets[8..].copy_from_slice(&self.0);
+        octets[8] ^= 0x02;
+        Ipv6Addr::from(octets)
+    }
+}
+
+impl fmt::Display for MacAddr64 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        for octet in &self.0 {
+            write!(fmt, "{:02X}", octet)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses 16 hex digits, optionally separated by colons every octet
+impl std::str::FromStr for MacAddr64 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits: String = s.chars().filter(|c| *c != ':').collect();
+        if digits.len() != 16 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::Decode(format!("invalid MAC address: {}", s)));
+        }
+        let mut octets = [0u8; 8];
+        for (i, octet) in octets.iter_mut().enumerate() {
+            *octet = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(MacAddr64(octets))
+    }
+}
+
+/// Pairing ID advertised in beacons, written as 8 hex digits
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PairingId(pub u32);
+
+impl fmt::Display for PairingId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        write!(fmt, "{:08X}", self.0)
+    }
+}
+
+impl std::str::FromStr for PairingId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 8 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::Decode(format!("invalid pairing ID: {}", s)));
+        }
+        Ok(PairingId(u32::from_str_radix(s, 16)?))
+    }
+}
+
+/// Credential such as the Route B password, redacted in `Debug` output and
+/// zeroed when dropped
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Secret(secret)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret(secret)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Secret(secret.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        write!(fmt, "Secret(***)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// 128-bit key passed to SKSETPSK and SKSETKEY, written as 32 hex digits.
+/// Redacted in `Debug` output and zeroed when dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key128(pub [u8; 16]);
+
+impl Key128 {
+    fn encode(&self) -> Secret {
+        // Sized upfront so that no partial copy is left behind by reallocation
+        let mut hex = String::with_capacity(self.0.len() * 2);
+        for byte in self.0.iter() {
+            let _ = write!(hex, "{:02X}", byte);
+        }
+        Secret(hex)
+    }
+}
+
+impl fmt::Debug for Key128 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        write!(fmt, "Key128(***)")
+    }
+}
+
+impl Drop for Key128 {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl std::str::FromStr for Key128 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 32 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::InvalidArgument(
+                "key must be 32 hex digits".to_string(),
+            ));
+        }
+        let mut key = Key128([0; 16]);
+        for (byte, digits) in key.0.iter_mut().zip(s.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(core::str::from_utf8(digits)?, 16)?;
+        }
+        Ok(key)
+    }
+}
+
+/// Route B authentication ID issued by the electric utility: 32 hex digits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteBId {
+    id: Secret,
+    pairing_id: PairingId,
+}
+
+impl RouteBId {
+    pub fn expose(&self) -> &str {
+        self.id.expose()
+    }
+
+    /// Pairing ID advertised by the meter this ID belongs to
+    pub fn pairing_id(&self) -> PairingId {
+        self.pairing_id
+    }
+}
+
+impl std::str::FromStr for RouteBId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 32 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::InvalidArgument(format!(
+                "Route B ID must be 32 hex digits, got {} characters",
+                s.chars().count()
+            )));
+        }
+        Ok(RouteBId {
+            id: Secret::from(s),
+            pairing_id: pairing_id(s)?,
+        })
+    }
+}
+
+/// Route B password issued by the electric utility: 12 alphanumeric
+/// characters
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteBPassword(Secret);
+
+impl RouteBPassword {
+    pub const LEN: usize = 12;
+
+    pub fn expose(&self) -> &str {
+        self.0.expose()
+    }
+}
+
+impl std::str::FromStr for RouteBPassword {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != Self::LEN || !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::InvalidArgument(format!(
+                "Route B password must be {} alphanumeric characters",
+                Self::LEN
+            )));
+        }
+        Ok(RouteBPassword(Secret::from(s)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SKPan {
+    pub channel: u8,
+    pub channel_page: u8,
+    pub pan_id: u16,
+    pub addr: MacAddr64,
+    pub lqi: u8,
+    pub pair_id: PairingId,
+}
+
+impl SKPan {
+    /// Received signal strength of the beacon in dBm
+    pub fn rssi(&self) -> f64 {
+        lqi_to_rssi(self.lqi)
+    }
+}
+
+/// Formats the PAN descriptor with the same keys as EPANDESC, one field per
+/// line, so that it can be cached and parsed back with `str::parse`.
+impl fmt::Display for SKPan {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        writeln!(fmt, "Channel:{:02X}", self.channel)?;
+        writeln!(fmt, "Channel Page:{:02X}", self.channel_page)?;
+        writeln!(fmt, "Pan ID:{:04X}", self.pan_id)?;
+        writeln!(fmt, "Addr:{}", self.addr)?;
+        writeln!(fmt, "LQI:{:02X}", self.lqi)?;
+        writeln!(fmt, "PairID:{}", self.pair_id)
+    }
+}
+
+impl std::str::FromStr for SKPan {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut channel = None;
+        let mut channel_page = None;
+        let mut pan_id = None;
+        let mut addr = None;
+        let mut lqi = None;
+        let mut pair_id = None;
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| Error::Decode(format!("missing key: {}", line)))?;
+            match key {
+                "Channel" => channel = Some(u8::from_str_radix(value, 16)?),
+                "Channel Page" => channel_page = Some(u8::from_str_radix(value, 16)?),
+                "Pan ID" => pan_id = Some(u16::from_str_radix(value, 16)?),
+                "Addr" => addr = Some(value.parse()?),
+                "LQI" => lqi = Some(u8::from_str_radix(value, 16)?),
+                "PairID" => pair_id = Some(value.parse()?),
+                _ => return Err(Error::Decode(format!("unknown key: {}", key))),
+            }
+        }
+        let missing = |key: &str| Error::Decode(format!("missing {} in PAN descriptor", key));
+        Ok(SKPan {
+            channel: channel.ok_or_else(|| missing("Channel"))?,
+            channel_page: channel_page.ok_or_else(|| missing("Channel Page"))?,
+            pan_id: pan_id.ok_or_else(|| missing("Pan ID"))?,
+            addr: addr.ok_or_else(|| missing("Addr"))?,
+            lqi: lqi.ok_or_else(|| missing("LQI"))?,
+            pair_id: pair_id.ok_or_else(|| missing("PairID"))?,
+        })
+    }
+}
+
+/// Derives the pairing ID advertised by the meter from a Route B ID,
+/// which is its last 8 characters.
+pub fn pairing_id(rbid: &str) -> Result<PairingId> {
+    rbid.get(rbid.len().saturating_sub(8)..)
+        .unwrap_or(rbid)
+        .parse()
+}
+
+/// Keeps only the PANs advertising `pair_id`, merges repeated beacons from
+/// the same coordinator into the one with the best LQI, and sorts the
+/// result by LQI in descending order.
+pub fn rank_pans(pans: Vec<SKPan>, pair_id: PairingId) -> Vec<SKPan> {
+    let mut ranked: Vec<SKPan> = vec![];
+    for pan in pans {
+        if pan.pair_id != pair_id {
+            continue;
+        }
+        match ranked.iter_mut().find(|known| known.addr == pan.addr) {
+            Some(known) if known.lqi < pan.lqi => *known = pan,
+            Some(_) => {}
+            None => ranked.push(pan),
+        }
+    }
+    ranked.sort_by_key(|pan| std::cmp::Reverse(pan.lqi));
+    ranked
+}
+
+/// Returns the PAN advertising `pair_id` with the best LQI
+pub fn best_pan(pans: Vec<SKPan>, pair_id: PairingId) -> Option<SKPan> {
+    rank_pans(pans, pair_id).into_iter().next()
+}
+
+/// Scan type of SKSCAN
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScanMode {
+    /// Energy detection scan, reported by EEDSCAN
+    EnergyDetect = 0,
+    /// Active scan with Information Element, reported by EPANDESC
+    Active = 2,
+    /// Active scan without Information Element, reported by EPANDESC
+    ActiveWithoutIE = 3,
+}
+
+/// Set of channels to scan, passed to SKSCAN as a bitmap whose least
+/// significant bit corresponds to channel 33
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelMask(u32);
+
+impl ChannelMask {
+    /// Lowest channel number of the 920MHz band
+    pub const MIN_CHANNEL: u8 = 33;
+    /// Highest channel number of the 920MHz band
+    pub const MAX_CHANNEL: u8 = 60;
+
+    /// All channels of the 920MHz band
+    pub fn all() -> Self {
+        Self::from_range(Self::MIN_CHANNEL..=Self::MAX_CHANNEL).unwrap()
+    }
+
+    pub fn empty() -> Self {
+        ChannelMask(0)
+    }
+
+    pub fn from_channels<I: IntoIterator<Item = u8>>(channels: I) -> Result<Self> {
+        channels
+            .into_iter()
+            .try_fold(Self::empty(), |mask, channel| mask.with_channel(channel))
+    }
+
+    pub fn from_range(channels: std::ops::RangeInclusive<u8>) -> Result<Self> {
+        Self::from_channels(channels)
+    }
+
+    /// Returns a mask with the given channel added
+    pub fn with_channel(self, channel: u8) -> Result<Self> {
+        if !(Self::MIN_CHANNEL..=Self::MAX_CHANNEL).contains(&channel) {
+            return Err(Error::InvalidArgument(format!(
+                "channel {} is out of range {}-{}",
+                channel,
+                Self::MIN_CHANNEL,
+                Self::MAX_CHANNEL
+            )));
+        }
+        Ok(ChannelMask(self.0 | 1 << (channel - Self::MIN_CHANNEL)))
+    }
+
+    pub fn contains(&self, channel: u8) -> bool {
+        self.channels().any(|c| c == channel)
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = u8> + '_ {
+        (Self::MIN_CHANNEL..=Self::MAX_CHANNEL)
+            .filter(move |channel| self.0 & 1 << (channel - Self::MIN_CHANNEL) != 0)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Scan duration per channel of SKSCAN. Each channel is scanned for
+/// 0.01 sec * (2^duration + 1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScanDuration(u8);
+
+impl ScanDuration {
+    pub const MAX: ScanDuration = ScanDuration(14);
+
+    pub fn new(duration: u8) -> Result<Self> {
+        if duration > Self::MAX.0 {
+            return Err(Error::InvalidArgument(format!(
+                "scan duration {} exceeds {}",
+                duration,
+                Self::MAX.0
+            )));
+        }
+        Ok(ScanDuration(duration))
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+
+    /// Time spent on each channel
+    pub fn per_channel(&self) -> Duration {
+        Duration::from_millis(10 * ((1u64 << self.0) + 1))
+    }
+
+    /// The next longer duration, if any
+    pub fn next(&self) -> Option<Self> {
+        Self::new(self.0 + 1).ok()
+    }
+}
+
+/// Received signal level of a channel, reported by EEDSCAN
+#[derive(Debug)]
+pub struct SKEnergy {
+    pub channel: u8,
+    pub lqi: u8,
+}
+
+impl SKEnergy {
+    /// Received signal strength in dBm
+    pub fn rssi(&self) -> f64 {
+        lqi_to_rssi(self.lqi)
+    }
+}
+
+/// Converts an LQI value reported by the module to RSSI in dBm
+pub fn lqi_to_rssi(lqi: u8) -> f64 {
+    0.275 * f64::from(lqi) - 104.27
+}
+
+/// Information of the module itself, reported by EINFO
+#[derive(Debug)]
+pub struct SKInfo {
+    /// IPv6 link-local address
+    pub ip_addr: Ipv6Addr,
+    pub addr: MacAddr64,
+    pub channel: u8,
+    pub pan_id: u16,
+    /// 16-bit short address
+    pub short_addr: u16,
+}
+
+/// Entry of the neighbor cache, reported by ENEIGHBOR
+#[derive(Debug)]
+pub struct SKNeighbor {
+    pub ip_addr: Ipv6Addr,
+    pub addr: MacAddr64,
+    /// 16-bit short address
+    pub short_addr: u16,
+}
+
+/// Ports bound to each handle, reported by EPORT.
+/// Handle `n` corresponds to index `n - 1`, and 0 means unused.
+#[derive(Debug)]
+pub struct SKPortTable {
+    pub udp: Vec<u16>,
+    pub tcp: Vec<u16>,
+}
+
+/// UDP handle (1-6) selecting a local port bound with SKUDPPORT
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UdpHandle(u8);
+
+impl UdpHandle {
+    pub const MIN: u8 = 1;
+    pub const MAX: u8 = 6;
+
+    pub fn new(handle: u8) -> Result<Self> {
+        if (Self::MIN..=Self::MAX).contains(&handle) {
+            Ok(UdpHandle(handle))
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "UDP handle {} is out of range {}-{}",
+                handle,
+                Self::MIN,
+                Self::MAX
+            )))
+        }
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+
+    fn index(&self) -> usize {
+        (self.0 - Self::MIN) as usize
+    }
+}
+
+/// TCP connection handle, reported by EHANDLE
+#[derive(Clone, Debug)]
+pub struct SKTcpHandle {
+    pub handle: u8,
+    pub ip_addr: Ipv6Addr,
+    pub rport: u16,
+    pub lport: u16,
+}
+
+#[derive(Debug)]
+pub enum SKEvent {
+    EVER(String),
+    ESREG(String),
+    EINFO(SKInfo),
+    EPONG(Ipv6Addr),
+    EEDSCAN(Vec<SKEnergy>),
+    /// Not sent by the module: reported by [`SKSTACK::read_event`] after the
+    /// device was disconnected and has been reopened at the given path, see
+    /// [`SKSTACK::reconnect`]. The credentials are set again, but the PANA
+    /// session is lost, so the caller has to join again.
+    DeviceReconnected(String),
+    EPANDESC(SKPan),
+    EVENT {
+        code: u8,
+        sender: Ipv6Addr,
+    },
+    ERXUDP {
+        sender: Ipv6Addr,
+        dest: Ipv6Addr,
+        rport: u16,
+        lport: u16,
+        /// Handle bound to `lport`, if known to [`SKSTACK`]. The bindings
+        /// are only known after [`SKSTACK::init`] or
+        /// [`SKSTACK::read_udp_ports`], so this is `None` until either is
+        /// called.
+        handle: Option<UdpHandle>,
+        sender_lla: MacAddr64,
+        secured: u8,
+        datalen: u16,
+        data: Vec<u8>,
+    },
+    /// Status change of a TCP connection
+    ETCP {
+        status: TcpStatus,
+        handle: u8,
+        /// Reported with [`TcpStatus::Connected`] only
+        peer: Option<SKTcpHandle>,
+    },
+    ERXTCP {
+        sender: Ipv6Addr,
+        rport: u16,
+        lport: u16,
+        datalen: u16,
+        data: Vec<u8>,
+    },
+    Unknown(String),
+}
+
+/// Status reported by ETCP
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum TcpStatus {
+    Connected = 1,
+    Closed = 3,
+    /// The local port is already in use
+    PortInUse = 4,
+    /// The data passed to SKSEND has been sent
+    SendCompleted = 5,
+}
+
+/// Virtual registers accessible through SKSREG
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// S02: logical channel number (33-60)
+    Channel,
+    /// S03: PAN ID
+    PanId,
+    /// S07: MAC frame counter (read-only)
+    FrameCounter,
+    /// S0A: pairing ID (8 hex characters)
+    PairingId,
+    /// S15: whether to respond to beacon requests
+    BeaconResponse,
+    /// S16: PANA session lifetime in seconds (at least 60)
+    PanaSessionLifetime,
+    /// S17: whether to re-authenticate automatically before the session expires
+    AutoReauthentication,
+    /// SA2: ERXUDP display format flag
+    ERXUDPFormat,
+    /// SA9: whether the transmit time limit is enabled
+    TransmitTimeLimit,
+    /// SFB: whether transmission is currently restricted by the time limit (read-only)
+    TransmitTimeLimited,
+    /// SFD: accumulated transmit time in milliseconds (read-only)
+    AccumulatedTransmitTime,
+    /// SFE: whether commands are echoed back
+    EchoBack,
+    /// SFF: whether the configuration is loaded from flash on startup
+    AutoLoad,
+}
+
+/// Value of a [`Register`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegisterValue {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    String(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RegisterKind {
+    Bool,
+    U8,
+    U16,
+    U32,
+    String,
+}
+
+impl Register {
+    /// Register name used in SKSREG commands
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::Channel => "S02",
+            Register::PanId => "S03",
+            Register::FrameCounter => "S07",
+            Register::PairingId => "S0A",
+            Register::BeaconResponse => "S15",
+            Register::PanaSessionLifetime => "S16",
+            Register::AutoReauthentication => "S17",
+            Register::ERXUDPFormat => "SA2",
+            Register::TransmitTimeLimit => "SA9",
+            Register::TransmitTimeLimited => "SFB",
+            Register::AccumulatedTransmitTime => "SFD",
+            Register::EchoBack => "SFE",
+            Register::AutoLoad => "SFF",
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Register::FrameCounter
+                | Register::TransmitTimeLimited
+                | Register::AccumulatedTransmitTime
+        )
+    }
+
+    fn kind(&self) -> RegisterKind {
+        match self {
+            Register::Channel => RegisterKind::U8,
+            Register::PanId => RegisterKind::U16,
+            Register::FrameCounter => RegisterKind::U32,
+            Register::PairingId => RegisterKind::String,
+            Register::BeaconResponse => RegisterKind::Bool,
+            Register::PanaSessionLifetime => RegisterKind::U32,
+            Register::AutoReauthentication => RegisterKind::Bool,
+            Register::ERXUDPFormat => RegisterKind::Bool,
+            Register::TransmitTimeLimit => RegisterKind::Bool,
+            Register::TransmitTimeLimited => RegisterKind::Bool,
+            Register::AccumulatedTransmitTime => RegisterKind::U32,
+            Register::EchoBack => RegisterKind::Bool,
+            Register::AutoLoad => RegisterKind::Bool,
+        }
+    }
+
+    /// Checks that the value has the type and range accepted by this register
+    pub fn validate(&self, value: &RegisterValue) -> Result<()> {
+        let invalid = |reason: &str| {
+            Err(Error::InvalidArgument(format!(
+                "{:?} for register {} ({:?}): {}",
+                value,
+                self.name(),
+                self,
+                reason
+            )))
+        };
+        if self.is_read_only() {
+            return invalid("read-only register");
+        }
+        if value.kind() != self.kind() {
+            return invalid("type mismatch");
+        }
+        match (self, value) {
+            (Register::Channel, RegisterValue::U8(channel)) if !(33..=60).contains(channel) => {
+                invalid("channel must be in 33-60")
+            }
+            (Register::PairingId, RegisterValue::String(id))
+                if id.len() != 8 || !id.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                invalid("pairing ID must be 8 hex characters")
+            }
+            (Register::PanaSessionLifetime, RegisterValue::U32(seconds)) if *seconds < 60 => {
+                invalid("lifetime must be at least 60 seconds")
+            }
+            (Register::ERXUDPFormat, _) => invalid(
+                "changes the format of the events read by SKSTACK, use write_erxudp_format instead",
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses the value field of an ESREG event for this register
+    pub fn decode(&self, value: &str) -> Result<RegisterValue> {
+        let value = value.trim();
+        Ok(match self.kind() {
+            RegisterKind::Bool => RegisterValue::Bool(u8::from_str_radix(value, 16)? != 0),
+            RegisterKind::U8 => RegisterValue::U8(u8::from_str_radix(value, 16)?),
+            RegisterKind::U16 => RegisterValue::U16(u16::from_str_radix(value, 16)?),
+            RegisterKind::U32 => RegisterValue::U32(u32::from_str_radix(value, 16)?),
+            RegisterKind::String => RegisterValue::String(value.to_string()),
+        })
+    }
+}
+
+impl RegisterValue {
+    fn kind(&self) -> RegisterKind {
+        match self {
+            RegisterValue::Bool(_) => RegisterKind::Bool,
+            RegisterValue::U8(_) => RegisterKind::U8,
+            RegisterValue::U16(_) => RegisterKind::U16,
+            RegisterValue::U32(_) => RegisterKind::U32,
+            RegisterValue::String(_) => RegisterKind::String,
+        }
+    }
+
+    /// Formats the value as an SKSREG argument
+    pub fn encode(&self) -> String {
+        match self {
+            RegisterValue::Bool(value) => (if *value { "1" } else { "0" }).to_string(),
+            RegisterValue::U8(value) => format!("{:X}", value),
+            RegisterValue::U16(value) => format!("{:X}", value),
+            RegisterValue::U32(value) => format!("{:X}", value),
+            RegisterValue::String(value) => value.clone(),
+        }
+    }
+}
+
+impl SKSTACK {
+    pub fn open(path: String, timeout: Option<Duration>) -> Result<Self> {
+        Self::open_with_config(path, &SerialConfig::default(), timeout)
+    }
+
+    /// Opens the module with the given serial line settings
+    pub fn open_with_config(
+        path: String,
+        config: &SerialConfig,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        Self::open_device(DeviceSelector::Path(path), config, timeout)
+    }
+
+    /// Opens the device currently matching the selector. The selector is
+    /// kept to find the device again in [`SKSTACK::reconnect`].
+    pub fn open_device(
+        selector: DeviceSelector,
+        config: &SerialConfig,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let port = open_port(&selector, config, timeout)?;
+        let config = port.config().clone();
+        let mut skstack = Self::with_transport(Box::new(port));
+        skstack.selector = Some(selector);
+        skstack.config = config;
+        Ok(skstack)
+    }
+
+    /// Uses an arbitrary transport instead of a serial port, e.g. a
+    /// [`Replayer`] to play back a transcript
+    pub fn with_transport(transport: Box<dyn Transport>) -> Self {
+        SKSTACK {
+            reader: std::io::BufReader::new(Port::new(transport)),
+            selector: None,
+            config: SerialConfig::default(),
+            reconnect_policy: None,
+            echo_back: true,
+            erxudp_format: ERXUDPFormat::Ascii,
+            keepalive: None,
+            udp_ports: [0; UdpHandle::MAX as usize],
+            last_alive: None,
+            tcp_handles: Vec::new(),
+            pending_events: VecDeque::new(),
+            password: None,
+            rbid: None,
+            psk: None,
+        }
+    }
+
+    /// Starts writing every byte exchanged with the module to `sink` as a
+    /// human-readable transcript, one timestamped and direction-tagged chunk
+    /// per line, which can be played back with [`Replayer`]. Secrets are
+    /// left out.
+    pub fn record_transcript<W: std::io::Write + Send + 'static>(&mut self, sink: W) {
+        self.reader.get_mut().start_recording(Box::new(sink));
+    }
+
+    /// Stops recording and flushes the transcript
+    pub fn stop_recording(&mut self) -> Result<()> {
+        self.reader.get_mut().stop_recording()?;
+        Ok(())
+    }
+
+    /// Enables or disables reopening the device from [`SKSTACK::read_event`]
+    /// when it is disconnected. Commands waiting for a reply at that time,
+    /// e.g. [`SKSTACK::join`], fail with `Error::Reconnected` instead.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect_policy = policy;
+    }
+
+    /// Finds the device again and reopens it with the same settings. The
+    /// module is initialized as in [`SKSTACK::init`], but keeps the current
+    /// echo back state and ERXUDP format, and the UDP port bindings and
+    /// credentials set before are set again. Returns the new device path.
+    pub fn reconnect(&mut self) -> Result<String> {
+        let settings = self.restored_settings();
+        self.reopen(&settings)
+    }
+
+    fn reconnect_with_policy(&mut self, policy: &ReconnectPolicy) -> Result<String> {
+        // Release the old port while waiting, as it keeps the device locked
+        self.close_transport();
+        // Taken once, as a failed attempt may leave the tracked state changed
+        let settings = self.restored_settings();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            std::thread::sleep(policy.interval);
+            match self.reopen(&settings) {
+                Ok(path) => return Ok(path),
+                Err(error) if attempt < policy.max_attempts => {
+                    warn!("failed to reconnect (attempt {}): {}", attempt, error)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn restored_settings(&self) -> RestoredSettings {
+        RestoredSettings {
+            echo_back: self.echo_back,
+            erxudp_format: self.erxudp_format,
+            udp_ports: self.udp_ports,
+        }
+    }
+
+    fn reopen(&mut self, settings: &RestoredSettings) -> Result<String> {
+        let selector = self.selector.clone().ok_or_else(|| {
+            Error::InvalidArgument("no device to reconnect to with a custom transport".to_string())
+        })?;
+        // The old port has to be closed before the device can be opened again
+        self.close_transport();
+        let timeout = self.reader.get_ref().transport().timeout();
+        let port = open_port(&selector, &self.config, timeout)?;
+        let path = port.port_name().unwrap_or_default().to_string();
+        self.reader.get_mut().replace_transport(Box::new(port));
+
+        // The module has been power cycled, so volatile settings are lost
+        self.initialize(settings.echo_back, settings.erxudp_format)?;
+        for (index, &port) in settings.udp_ports.iter().enumerate() {
+            if self.udp_ports[index] != port {
+                self.write_udp_port(UdpHandle(index as u8 + UdpHandle::MIN), port)?;
+            }
+        }
+        if let Some(password) = self.password.clone() {
+            self.set_password(&password)?;
+        }
+        if let Some(id) = self.rbid.clone() {
+            self.set_rbid(&id)?;
+        }
+        if let Some(psk) = self.psk.clone() {
+            self.set_psk(&psk)?;
+        }
+        Ok(path)
+    }
+
+    fn close_transport(&mut self) {
+        self.reader.get_mut().close_transport();
+        let buffered = self.reader.buffer().len();
+        self.reader.consume(buffered);
+    }
+
+    /// Resets the protocol stack (SKRESET), terminating any PANA session.
+    /// Lines received before the reply are skipped, so this works
+    /// regardless of the echo back state.
+    pub fn reset(&mut self) -> Result<()> {
+        self.write(b"SKRESET\r\n")?;
+        loop {
+            let line = self.read_line()?;
+            if line == b"OK" {
+                return Ok(());
+            }
+            if line.starts_with(b"FAIL ER") {
+                let line = String::from_utf8_lossy(&line).into_owned();
+                return check_fail(&line);
+            }
+        }
+    }
+
+    /// Brings the module into a known state after opening it: discards stale
+    /// input, resets the stack, disables echo back, selects the ASCII ERXUDP
+    /// format and checks that the module answers SKVER. Returns the firmware
+    /// version.
+    pub fn init(&mut self) -> Result<String> {
+        self.initialize(false, ERXUDPFormat::Ascii)
+    }
+
+    fn initialize(&mut self, echo_back: bool, erxudp_format: ERXUDPFormat) -> Result<String> {
+        self.discard_input()?;
+        self.reset()?;
+        if self.detect_echo_back()? != echo_back {
+            self.set_echo_back(echo_back)?;
+        }
+        if self.read_erxudp_format()? != erxudp_format {
+            self.write_erxudp_format(erxudp_format)?;
+        }
+        self.read_udp_ports()?;
+        self.last_alive = None;
+        self.tcp_handles.clear();
+        self.version()
+    }
+
+    fn discard_input(&mut self) -> Result<()> {
+        self.reader.get_mut().transport_mut().discard_input()?;
+        let buffered = self.reader.buffer().len();
+        self.reader.consume(buffered);
+        Ok(())
+    }
+
+    pub fn version(&mut self) -> Result<String> {
+        self.write(b"SKVER\r\n")?;
+        self.consume_echo_back()?;
+        let version = match self.next_event()? {
+            SKEvent::EVER(version) => version,
+            other => return Err(Error::UnexpectedEvent(other)),
+        };
+        self.consume_ok()?;
+        Ok(version)
+    }
 
-impl std::error::Error for Error {}
+    /// Reads the module's own addresses and PAN settings (SKINFO)
+    pub fn info(&mut self) -> Result<SKInfo> {
+        self.write(b"SKINFO\r\n")?;
+        self.consume_echo_back()?;
+        let info = match self.next_event()? {
+            SKEvent::EINFO(info) => info,
+            other => return Err(Error::UnexpectedEvent(other)),
+        };
+        self.consume_ok()?;
+        Ok(info)
+    }
 
-impl From<std::io::Error> for Error {
-    fn from(error: std::io::Error) -> Self {
-        Error::Io(error)
+    /// Reads the IPv6 address list (SKTABLE 1)
+    pub fn address_table(&mut self) -> Result<Vec<Ipv6Addr>> {
+        let lines = self.read_table('1', "EADDR")?;
+        lines.iter().map(|line| Ok(line.trim().parse()?)).collect()
     }
-}
 
-impl From<tty::Error> for Error {
-    fn from(error: tty::Error) -> Self {
-        Error::Io(error.into())
+    /// Reads the neighbor cache (SKTABLE 2)
+    pub fn neighbor_table(&mut self) -> Result<Vec<SKNeighbor>> {
+        let lines = self.read_table('2', "ENEIGHBOR")?;
+        lines.iter().map(|line| parse_neighbor(line)).collect()
     }
-}
 
-impl From<std::str::Utf8Error> for Error {
-    fn from(error: std::str::Utf8Error) -> Self {
-        Error::StrDecode(error)
+    /// Reads the ports bound to UDP and TCP handles (SKTABLE E)
+    pub fn port_table(&mut self) -> Result<SKPortTable> {
+        let lines = self.read_table('E', "EPORT")?;
+        parse_port_table(&lines)
     }
-}
 
-impl From<std::num::ParseIntError> for Error {
-    fn from(error: std::num::ParseIntError) -> Self {
-        Error::ParseInt(error)
+    /// Reads the TCP connection handles (SKTABLE F)
+    pub fn tcp_handle_table(&mut self) -> Result<Vec<SKTcpHandle>> {
+        let lines = self.read_table('F', "EHANDLE")?;
+        lines.iter().map(|line| parse_tcp_handle(line)).collect()
     }
-}
 
-pub struct SKSTACK {
-    reader: std::io::BufReader<tty::TTYPort>,
-}
+    /// Issues SKTABLE and returns the lines between the header and OK
+    fn read_table(&mut self, mode: char, header: &str) -> Result<Vec<String>> {
+        self.write_str(format!("SKTABLE {}\r\n", mode))?;
+        self.consume_echo_back()?;
+        let line = self.read_line_str()?;
+        if line != header {
+            return Err(Error::Decode(format!(
+                "expected {} but got: {}",
+                header, line
+            )));
+        }
+        let mut lines = vec![];
+        loop {
+            let line = self.read_line_str()?;
+            if line == "OK" {
+                break;
+            }
+            lines.push(line);
+        }
+        Ok(lines)
+    }
 
-#[derive(Debug)]
-pub struct SKPan {
-    pub channel: u8,
-    pub channel_page: u8,
-    pub pan_id: u16,
-    pub addr: String,
-    pub lqi: u8,
-    pub pair_id: String,
-}
+    /// Sends an ICMPv6 echo request (SKPING) and waits for EPONG from the
+    /// peer. Returns the round-trip time, or a timeout error if no reply
+    /// arrives in time. Other events received while waiting are returned by
+    /// the following calls to [`SKSTACK::read_event`].
+    pub fn ping(&mut self, ip_v6_addr: &Ipv6Addr, timeout: Duration) -> Result<Duration> {
+        let start = Instant::now();
+        self.write_str(format!("SKPING {}\r\n", format_ipv6_addr(ip_v6_addr)))?;
+        self.consume_echo_back()?;
+        self.consume_ok()?;
+        self.with_deadline(start + timeout, |skstack| skstack.wait_pong(ip_v6_addr))?;
+        Ok(start.elapsed())
+    }
 
-#[derive(Debug)]
-pub enum SKEvent {
-    EVER(String),
-    EPANDESC(SKPan),
-    EVENT {
-        code: u8,
-        sender: String,
-    },
-    ERXUDP {
-        sender: String,
-        dest: String,
-        rport: u16,
-        lport: u16,
-        sender_lla: String,
-        secured: u8,
-        datalen: u16,
-        data: Vec<u8>,
-    },
-    Unknown(String),
-}
+    fn wait_pong(&mut self, ip_v6_addr: &Ipv6Addr) -> Result<()> {
+        loop {
+            match self.next_event()? {
+                SKEvent::EPONG(sender) if sender == *ip_v6_addr => return Ok(()),
+                // Left for the application, e.g. replies from the meter
+                other => self.pending_events.push_back(other),
+            }
+        }
+    }
 
-impl SKSTACK {
-    pub fn open(path: String, timeout: Option<Duration>) -> Result<Self> {
-        let port = TTYPort::open(path, 115_200, timeout)?;
-        let reader = std::io::BufReader::new(port);
-        Ok(SKSTACK { reader })
+    /// Enables or disables the keepalive checked by [`SKSTACK::check_keepalive`]
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+        self.last_alive = None;
     }
 
-    pub fn version(&mut self) -> Result<String> {
-        self.write(b"SKVER\r\n")?;
-        self.read_line_str()?;
-        let version = match self.read_event()? {
-            SKEvent::EVER(version) => version,
-            other => return Err(Error::UnexpectedEvent(other)),
+    /// Pings the keepalive peer if it has been silent for longer than the
+    /// configured interval, and returns `Error::LinkDown` if it does not
+    /// answer. Intended to be called periodically from the application loop,
+    /// e.g. before sending a request. Does nothing if keepalive is disabled.
+    pub fn check_keepalive(&mut self) -> Result<()> {
+        let keepalive = match &self.keepalive {
+            Some(keepalive) => keepalive.clone(),
+            None => return Ok(()),
         };
-        self.consume_ok()?;
-        Ok(version)
+        if let Some(last_alive) = self.last_alive {
+            if last_alive.elapsed() < keepalive.interval {
+                return Ok(());
+            }
+        }
+        match self.ping(&keepalive.ip_v6_addr, keepalive.timeout) {
+            Ok(_) => Ok(()),
+            Err(error) if error.is_timeout() => Err(Error::LinkDown(keepalive.ip_v6_addr)),
+            Err(error) => Err(error),
+        }
     }
 
-    pub fn set_password<S: Into<String>>(&mut self, password: S) -> Result<()> {
-        let password: String = password.into();
-        self.write_str(format!("SKSETPWD {:X} {}\r\n", password.len(), password))?;
-        self.read_line_str()?;
-        self.consume_ok()?;
+    /// Tracks whether the keepalive peer is alive from received events
+    fn observe_keepalive(&mut self, event: &SKEvent) {
+        let peer = match &self.keepalive {
+            Some(keepalive) => keepalive.ip_v6_addr,
+            None => return,
+        };
+        match event {
+            SKEvent::EPONG(sender) | SKEvent::ERXUDP { sender, .. } if *sender == peer => {
+                self.last_alive = Some(Instant::now());
+            }
+            // PANA session termination requested by the peer, terminated
+            // or expired
+            SKEvent::EVENT {
+                code: 0x26..=0x29, ..
+            } => {
+                self.last_alive = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets the password for PANA authentication (SKSETPWD)
+    pub fn set_password(&mut self, password: &RouteBPassword) -> Result<()> {
+        let command = format!("SKSETPWD {:X}", RouteBPassword::LEN);
+        self.write_secret_command(&command, &password.0)?;
+        self.password = Some(password.clone());
         Ok(())
     }
 
-    pub fn set_rbid<S: Into<String>>(&mut self, id: S) -> Result<()> {
-        let id: String = id.into();
-        self.write_str(format!("SKSETRBID {}\r\n", id))?;
-        self.read_line_str()?;
-        self.consume_ok()?;
+    /// Sets the PSK used for PANA authentication directly (SKSETPSK), instead
+    /// of deriving it in the module from the password given to
+    /// [`SKSTACK::set_password`]
+    pub fn set_psk(&mut self, psk: &Key128) -> Result<()> {
+        let command = format!("SKSETPSK {:X}", psk.0.len());
+        self.write_secret_command(&command, &psk.encode())?;
+        self.psk = Some(psk.clone());
+        Ok(())
+    }
+
+    /// Registers a MAC layer encryption key under the key index (SKSETKEY).
+    /// Keys are normally distributed by PANA, so this is meant for tests.
+    pub fn set_mac_key(&mut self, index: u8, key: &Key128) -> Result<()> {
+        let command = format!("SKSETKEY {:02X}", index);
+        self.write_secret_command(&command, &key.encode())
+    }
+
+    pub fn set_rbid(&mut self, id: &RouteBId) -> Result<()> {
+        self.write_secret_command("SKSETRBID", &id.id)?;
+        self.rbid = Some(id.clone());
         Ok(())
     }
 
-    pub fn scan(&mut self, mode: u8, channel_mask: u32, duration: u8) -> Result<Vec<SKPan>> {
+    /// Performs an active scan and returns the PANs that responded
+    pub fn scan(
+        &mut self,
+        mode: ScanMode,
+        channel_mask: ChannelMask,
+        duration: ScanDuration,
+    ) -> Result<Vec<SKPan>> {
+        if mode == ScanMode::EnergyDetect {
+            return Err(Error::InvalidArgument(
+                "use energy_detect_scan for ED scan".to_string(),
+            ));
+        }
         let mut found: Vec<SKPan> = vec![];
-        self.write_str(format!(
-            "SKSCAN {:X} {:X} {:X}\r\n",
-            mode, channel_mask, duration
-        ))?;
-        self.read_line_str()?;
-        self.consume_ok()?;
+        self.start_scan(mode, channel_mask, duration)?;
         loop {
-            let event = self.read_event()?;
+            let event = self.next_event()?;
             match event {
                 SKEvent::EVENT { code: 0x20, .. } => {
-                    match self.read_event()? {
+                    match self.next_event()? {
                         SKEvent::EPANDESC(pan) => {
                             found.push(pan);
                         }
@@ -166,29 +1367,292 @@ impl SKSTACK {
         Ok(found)
     }
 
+    /// Performs an energy detection scan and returns the signal level of
+    /// each scanned channel
+    pub fn energy_detect_scan(
+        &mut self,
+        channel_mask: ChannelMask,
+        duration: ScanDuration,
+    ) -> Result<Vec<SKEnergy>> {
+        self.start_scan(ScanMode::EnergyDetect, channel_mask, duration)?;
+        let mut result = None;
+        let mut completed = false;
+        while result.is_none() || !completed {
+            match self.next_event()? {
+                SKEvent::EEDSCAN(energies) => result = Some(energies),
+                SKEvent::EVENT { code: 0x1F, .. } => completed = true,
+                other => return Err(Error::UnexpectedEvent(other)),
+            }
+        }
+        Ok(result.unwrap_or_default())
+    }
+
+    /// Repeats active scans from `duration` up to `max_duration`, widening the
+    /// duration each time, until at least one PAN is found. Returns an empty
+    /// list if nothing is found even with `max_duration`.
+    pub fn scan_escalating(
+        &mut self,
+        mode: ScanMode,
+        channel_mask: ChannelMask,
+        duration: ScanDuration,
+        max_duration: ScanDuration,
+    ) -> Result<Vec<SKPan>> {
+        let mut duration = duration;
+        loop {
+            debug!("scanning (duration = {})", duration.value());
+            let found = self.scan(mode, channel_mask, duration)?;
+            if !found.is_empty() {
+                return Ok(found);
+            }
+            match duration.next() {
+                Some(next) if next <= max_duration => duration = next,
+                _ => return Ok(found),
+            }
+        }
+    }
+
+    fn start_scan(
+        &mut self,
+        mode: ScanMode,
+        channel_mask: ChannelMask,
+        duration: ScanDuration,
+    ) -> Result<()> {
+        self.write_str(format!(
+            "SKSCAN {:X} {:X} {:X}\r\n",
+            mode as u8,
+            channel_mask.bits(),
+            duration.value()
+        ))?;
+        self.consume_echo_back()?;
+        self.consume_ok()?;
+        Ok(())
+    }
+
     pub fn set_timeout(&mut self, timeout: Option<Duration>) {
-        self.reader.get_mut().set_timeout(timeout);
+        self.reader.get_mut().transport_mut().set_timeout(timeout);
+    }
+
+    /// Runs `f` with every read and write bounded by `deadline` instead of
+    /// the port-wide timeout. The time left is tracked across partial reads,
+    /// so a slowly received line cannot extend the operation.
+    fn with_deadline<T>(
+        &mut self,
+        deadline: Instant,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let port = self.reader.get_mut().transport_mut();
+        let saved_timeout = port.timeout();
+        let saved_deadline = port.deadline();
+        let deadline = saved_deadline.map_or(deadline, |saved| saved.min(deadline));
+        port.set_timeout(None);
+        port.set_deadline(Some(deadline));
+        let result = f(self);
+        let port = self.reader.get_mut().transport_mut();
+        port.set_timeout(saved_timeout);
+        port.set_deadline(saved_deadline);
+        result
+    }
+
+    /// Writes a register after validating the value (SKSREG). Echo back is
+    /// set through [`SKSTACK::set_echo_back`] to keep the tracked state.
+    pub fn set_register(&mut self, reg: Register, value: RegisterValue) -> Result<()> {
+        reg.validate(&value)?;
+        match (reg, value) {
+            (Register::EchoBack, RegisterValue::Bool(enabled)) => self.set_echo_back(enabled),
+            (reg, value) => self.write_register(reg.name(), &value.encode()),
+        }
     }
-    pub fn set_register(&mut self, reg: &str, value: String) -> Result<()> {
+
+    /// Writes a register by name without validating the value (SKSREG).
+    /// Registers whose state is tracked by SKSTACK are rejected, use
+    /// [`SKSTACK::set_echo_back`] and [`SKSTACK::write_erxudp_format`]
+    /// instead.
+    pub fn set_register_raw(&mut self, reg: &str, value: &str) -> Result<()> {
+        for tracked in [Register::EchoBack, Register::ERXUDPFormat].iter() {
+            if reg.eq_ignore_ascii_case(tracked.name()) {
+                return Err(Error::InvalidArgument(format!(
+                    "register {} ({:?}) is tracked by SKSTACK and cannot be written directly",
+                    tracked.name(),
+                    tracked
+                )));
+            }
+        }
+        self.write_register(reg, value)
+    }
+
+    fn write_register(&mut self, reg: &str, value: &str) -> Result<()> {
         self.write_str(format!("SKSREG {} {}\r\n", reg, value))?;
-        self.read_line_str()?;
+        self.consume_echo_back()?;
+        self.consume_ok()?;
+        Ok(())
+    }
+
+    /// Reads a register (SKSREG without a value)
+    pub fn get_register(&mut self, reg: Register) -> Result<RegisterValue> {
+        let value = self.get_register_raw(reg.name())?;
+        reg.decode(&value)
+    }
+
+    /// Reads a register by name and returns the raw value field of ESREG
+    pub fn get_register_raw(&mut self, reg: &str) -> Result<String> {
+        self.write_str(format!("SKSREG {}\r\n", reg))?;
+        self.consume_echo_back()?;
+        let value = match self.next_event()? {
+            SKEvent::ESREG(value) => value,
+            other => return Err(Error::UnexpectedEvent(other)),
+        };
+        self.consume_ok()?;
+        Ok(value)
+    }
+
+    /// Saves the current register values and credentials in the module's
+    /// flash memory (SKSAVE). The flash has a limited number of write cycles.
+    pub fn save(&mut self) -> Result<()> {
+        self.flash_command("SKSAVE")
+    }
+
+    /// Restores the settings saved in flash memory (SKLOAD)
+    pub fn load(&mut self) -> Result<()> {
+        self.flash_command("SKLOAD")?;
+        // The saved settings may include SFE
+        self.detect_echo_back()?;
+        Ok(())
+    }
+
+    /// Erases the settings saved in flash memory (SKERASE)
+    pub fn erase(&mut self) -> Result<()> {
+        self.flash_command("SKERASE")
+    }
+
+    fn flash_command(&mut self, command: &'static str) -> Result<()> {
+        self.write_str(format!("{}\r\n", command))?;
+        self.consume_echo_back()?;
+        self.consume_ok().map_err(|error| match error {
+            Error::Fail(code) => Error::Flash { command, code },
+            error => error,
+        })
+    }
+
+    /// Returns whether the module loads the saved settings on startup
+    /// (register SFF)
+    pub fn auto_load(&mut self) -> Result<bool> {
+        match self.get_register(Register::AutoLoad)? {
+            RegisterValue::Bool(enabled) => Ok(enabled),
+            other => Err(Error::Decode(format!("unexpected SFF value: {:?}", other))),
+        }
+    }
+
+    /// Enables or disables loading the saved settings on startup (register
+    /// SFF). Call [`SKSTACK::save`] afterwards to make it persistent.
+    pub fn set_auto_load(&mut self, enabled: bool) -> Result<()> {
+        self.set_register(Register::AutoLoad, RegisterValue::Bool(enabled))
+    }
+
+    /// Enables or disables echo back of commands (register SFE)
+    pub fn set_echo_back(&mut self, enabled: bool) -> Result<()> {
+        self.write_str(format!(
+            "SKSREG {} {}\r\n",
+            Register::EchoBack.name(),
+            RegisterValue::Bool(enabled).encode()
+        ))?;
+        // The command line itself is echoed according to the previous setting
+        self.consume_echo_back()?;
+        self.echo_back = enabled;
+        self.consume_ok()?;
+        Ok(())
+    }
+
+    /// Returns whether commands are currently assumed to be echoed back
+    pub fn echo_back(&self) -> bool {
+        self.echo_back
+    }
+
+    /// Probes the module with SKVER to find out whether echo back is enabled
+    /// and updates the tracked state accordingly.
+    pub fn detect_echo_back(&mut self) -> Result<bool> {
+        self.write(b"SKVER\r\n")?;
+        let line = self.read_line_str()?;
+        if line == "SKVER" {
+            self.echo_back = true;
+            match self.next_event()? {
+                SKEvent::EVER(_) => {}
+                other => return Err(Error::UnexpectedEvent(other)),
+            }
+        } else if line.starts_with("EVER ") {
+            self.echo_back = false;
+        } else {
+            return Err(Error::Decode(format!(
+                "unexpected reply to SKVER: {}",
+                line
+            )));
+        }
+        self.consume_ok()?;
+        Ok(self.echo_back)
+    }
+
+    /// Returns the ERXUDP data format currently assumed when reading events
+    pub fn erxudp_format(&self) -> ERXUDPFormat {
+        self.erxudp_format
+    }
+
+    /// Reads the ERXUDP data format from the module (ROPT) and updates the
+    /// tracked state accordingly.
+    pub fn read_erxudp_format(&mut self) -> Result<ERXUDPFormat> {
+        self.write(b"ROPT\r\n")?;
+        self.consume_echo_back()?;
+        let reply = self.read_line_str()?;
+        let mode = reply
+            .strip_prefix("OK ")
+            .ok_or_else(|| Error::ExpectOK(reply.clone()))?;
+        let format = ERXUDPFormat::try_from(u8::from_str_radix(mode.trim(), 16)?)?;
+        self.erxudp_format = format;
+        Ok(format)
+    }
+
+    /// Writes the ERXUDP data format (WOPT).
+    /// Note that the setting is saved in the module's flash memory, which
+    /// has a limited number of write cycles.
+    pub fn write_erxudp_format(&mut self, format: ERXUDPFormat) -> Result<()> {
+        self.write_str(format!("WOPT {:02X}\r\n", format as u8))?;
+        self.consume_echo_back()?;
         self.consume_ok()?;
+        self.erxudp_format = format;
         Ok(())
     }
 
-    pub fn get_link_local_addr(&mut self, addr: String) -> Result<String> {
+    /// Asks the module to convert a MAC address into an IPv6 link-local
+    /// address (SKLL64). [`MacAddr64::link_local_addr`] gives the same result
+    /// without a serial round-trip.
+    pub fn get_link_local_addr(&mut self, addr: &MacAddr64) -> Result<Ipv6Addr> {
         self.write_str(format!("SKLL64 {}\r\n", addr))?;
-        self.read_line_str()?;
-        let addr = self.read_line_str()?;
-        Ok(addr)
+        self.consume_echo_back()?;
+        let reply = self.read_line_str()?;
+        check_fail(&reply)?;
+        let ip_v6_addr: Ipv6Addr = reply
+            .trim()
+            .parse()
+            .map_err(|_| Error::Decode(format!("invalid reply to SKLL64: {}", reply)))?;
+        if MacAddr64::from_link_local_addr(&ip_v6_addr).is_none() {
+            return Err(Error::Decode(format!(
+                "SKLL64 returned a non link-local address: {}",
+                reply
+            )));
+        }
+        if ip_v6_addr != addr.link_local_addr() {
+            warn!(
+                "SKLL64 returned {} for {}, which differs from the local conversion",
+                reply, addr
+            );
+        }
+        Ok(ip_v6_addr)
     }
 
-    pub fn join(&mut self, ip_v6_addr: &str) -> Result<()> {
-        self.write_str(format!("SKJOIN {}\r\n", ip_v6_addr))?;
-        self.read_line_str()?;
+    pub fn join(&mut self, ip_v6_addr: &Ipv6Addr) -> Result<()> {
+        self.write_str(format!("SKJOIN {}\r\n", format_ipv6_addr(ip_v6_addr)))?;
+        self.consume_echo_back()?;
         self.consume_ok()?;
         loop {
-            let event = self.read_event()?;
+            let event = self.next_event()?;
             match event {
                 SKEvent::EVENT { code: 0x25, .. } => {
                     break;
@@ -200,27 +1664,241 @@ impl SKSTACK {
         Ok(())
     }
 
+    /// Same as [`SKSTACK::join`], but fails with a timeout error if the PANA
+    /// authentication does not complete within `timeout`
+    pub fn join_with_timeout(&mut self, ip_v6_addr: &Ipv6Addr, timeout: Duration) -> Result<()> {
+        self.with_deadline(Instant::now() + timeout, |skstack| skstack.join(ip_v6_addr))
+    }
+
+    /// Sets the channel and PAN ID of the given PAN and joins its coordinator.
+    /// Returns the IPv6 address of the coordinator.
+    pub fn join_pan(&mut self, pan: &SKPan) -> Result<Ipv6Addr> {
+        self.set_register(Register::Channel, RegisterValue::U8(pan.channel))?;
+        self.set_register(Register::PanId, RegisterValue::U16(pan.pan_id))?;
+        let ip_v6_addr = pan.addr.link_local_addr();
+        self.join(&ip_v6_addr)?;
+        Ok(ip_v6_addr)
+    }
+
+    /// Joins the cached PAN if given, and falls back to scanning for the PAN
+    /// advertising `pair_id` if there is no cache, it advertises another
+    /// pairing ID or joining it fails.
+    /// Returns the joined PAN, to be cached for the next time, and the IPv6
+    /// address of its coordinator.
+    pub fn connect(
+        &mut self,
+        cached: Option<&SKPan>,
+        pair_id: PairingId,
+    ) -> Result<(SKPan, Ipv6Addr)> {
+        match cached {
+            // e.g. cached before the Route B ID changed
+            Some(pan) if pan.pair_id != pair_id => {
+                debug!("cached PAN has pairing ID {}, scanning", pan.pair_id)
+            }
+            Some(pan) => match self.join_pan(pan) {
+                Ok(ip_v6_addr) => return Ok((pan.clone(), ip_v6_addr)),
+                Err(error) => warn!("failed to join cached PAN, scanning: {}", error),
+            },
+            None => {}
+        }
+        let found = self.scan_escalating(
+            ScanMode::Active,
+            ChannelMask::all(),
+            ScanDuration(4),
+            ScanDuration::MAX,
+        )?;
+        let pan = best_pan(found, pair_id)
+            .ok_or_else(|| Error::Decode(format!("no PAN found for pairing ID {}", pair_id)))?;
+        let ip_v6_addr = self.join_pan(&pan)?;
+        Ok((pan, ip_v6_addr))
+    }
+
+    /// Starts a PAN as its coordinator and PANA authentication agent
+    /// (SKSTART), e.g. to emulate a smart meter. Set the credentials
+    /// accepted from joining devices with [`SKSTACK::set_password`] first.
+    pub fn start_coordinator(&mut self, config: &CoordinatorConfig) -> Result<()> {
+        self.set_register(Register::Channel, RegisterValue::U8(config.channel))?;
+        self.set_register(Register::PanId, RegisterValue::U16(config.pan_id))?;
+        if let Some(pairing_id) = config.pairing_id {
+            self.set_register(
+                Register::PairingId,
+                RegisterValue::String(pairing_id.to_string()),
+            )?;
+        }
+        // Let devices find the PAN with an active scan
+        self.set_register(Register::BeaconResponse, RegisterValue::Bool(true))?;
+        self.write(b"SKSTART\r\n")?;
+        self.consume_echo_back()?;
+        self.consume_ok()
+    }
+
+    /// Waits until a device completes PANA authentication with this
+    /// coordinator and returns its IPv6 address. Other events received while
+    /// waiting are discarded.
+    pub fn accept_join(&mut self) -> Result<Ipv6Addr> {
+        loop {
+            let event = self.read_event()?;
+            match event {
+                SKEvent::EVENT { code: 0x25, sender } => return Ok(sender),
+                SKEvent::EVENT { code: 0x24, .. } => return Err(Error::UnexpectedEvent(event)),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Binds a local UDP port to the handle (SKUDPPORT), so that ERXUDP
+    /// events for the port are received and it can be used with
+    /// [`SKSTACK::send_udp`]
+    pub fn open_udp_port(&mut self, handle: UdpHandle, port: u16) -> Result<()> {
+        if port == 0 {
+            return Err(Error::InvalidArgument(
+                "UDP port 0 is reserved to close a handle".to_string(),
+            ));
+        }
+        self.write_udp_port(handle, port)
+    }
+
+    /// Unbinds the local UDP port of the handle
+    pub fn close_udp_port(&mut self, handle: UdpHandle) -> Result<()> {
+        self.write_udp_port(handle, 0)
+    }
+
+    fn write_udp_port(&mut self, handle: UdpHandle, port: u16) -> Result<()> {
+        self.write_str(format!("SKUDPPORT {:X} {:04X}\r\n", handle.value(), port))?;
+        self.consume_echo_back()?;
+        self.consume_ok()?;
+        self.udp_ports[handle.index()] = port;
+        Ok(())
+    }
+
+    /// Reads the ports currently bound to UDP handles from the module, e.g.
+    /// the defaults set up at startup
+    pub fn read_udp_ports(&mut self) -> Result<()> {
+        let table = self.port_table()?;
+        self.udp_ports = [0; UdpHandle::MAX as usize];
+        for (slot, port) in self.udp_ports.iter_mut().zip(table.udp) {
+            *slot = port;
+        }
+        Ok(())
+    }
+
+    /// Returns the handle bound to the local UDP port, as of the last
+    /// [`SKSTACK::init`] or [`SKSTACK::read_udp_ports`] and the ports opened
+    /// or closed since
+    pub fn udp_handle(&self, port: u16) -> Option<UdpHandle> {
+        if port == 0 {
+            return None;
+        }
+        self.udp_ports
+            .iter()
+            .position(|&bound| bound == port)
+            .map(|index| UdpHandle(index as u8 + UdpHandle::MIN))
+    }
+
     pub fn send_udp(
         &mut self,
-        handle: u8,
+        handle: UdpHandle,
         port: u16,
-        ip_v6_addr: &str,
+        ip_v6_addr: &Ipv6Addr,
         bytes: &[u8],
     ) -> Result<()> {
         // TODO: Support SEC field
         self.write_str(format!(
             "SKSENDTO {:X} {} {:04X} 1 {:04X} {}\r\n",
-            handle,
-            ip_v6_addr,
+            handle.value(),
+            format_ipv6_addr(ip_v6_addr),
             port,
             bytes.len(),
             unsafe { std::str::from_utf8_unchecked(bytes) },
         ))?;
-        self.read_line_str()?;
+        self.consume_echo_back()?;
 
         Ok(())
     }
 
+    /// Opens a TCP connection (SKCONNECT) and waits until it is established.
+    /// Fails with `Error::UnexpectedEvent` if the module reports
+    /// [`TcpStatus::PortInUse`], or closes a handle other than those of the
+    /// connections already established.
+    pub fn tcp_connect(
+        &mut self,
+        ip_v6_addr: &Ipv6Addr,
+        rport: u16,
+        lport: u16,
+    ) -> Result<TcpConnection<'_>> {
+        self.write_str(format!(
+            "SKCONNECT {} {:04X} {:04X}\r\n",
+            format_ipv6_addr(ip_v6_addr),
+            rport,
+            lport
+        ))?;
+        self.consume_echo_back()?;
+        self.consume_ok()?;
+        // Handles of other connections, which may close while waiting
+        let mut others = self.tcp_handles.clone();
+        loop {
+            let event = self.next_event()?;
+            match event {
+                SKEvent::ETCP {
+                    status: TcpStatus::Connected,
+                    peer: Some(peer),
+                    ..
+                } if peer.ip_addr == *ip_v6_addr && peer.rport == rport && peer.lport == lport => {
+                    return Ok(TcpConnection {
+                        skstack: self,
+                        peer,
+                        received: Vec::new(),
+                        closed: false,
+                    });
+                }
+                SKEvent::ETCP {
+                    status: TcpStatus::Connected,
+                    handle,
+                    ..
+                } => others.push(handle),
+                SKEvent::ETCP {
+                    status: TcpStatus::PortInUse,
+                    ..
+                } => return Err(Error::UnexpectedEvent(event)),
+                SKEvent::ETCP {
+                    status: TcpStatus::Closed,
+                    handle,
+                    ..
+                } if !others.contains(&handle) => return Err(Error::UnexpectedEvent(event)),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Sends data over a TCP connection (SKSEND)
+    fn tcp_send(&mut self, handle: u8, bytes: &[u8]) -> Result<()> {
+        let mut line = format!("SKSEND {:X} {:04X} ", handle, bytes.len()).into_bytes();
+        line.extend_from_slice(bytes);
+        line.extend_from_slice(b"\r\n");
+        self.write(&line)?;
+        self.consume_echo_back()?;
+        self.consume_ok()
+    }
+
+    /// Closes a TCP connection (SKCLOSE) and waits until it is closed
+    fn tcp_close(&mut self, handle: u8) -> Result<()> {
+        self.write_str(format!("SKCLOSE {:X}\r\n", handle))?;
+        self.consume_echo_back()?;
+        self.consume_ok()?;
+        loop {
+            if let SKEvent::ETCP {
+                status: TcpStatus::Closed,
+                handle: closed,
+                ..
+            } = self.next_event()?
+            {
+                if closed == handle {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
     pub fn receive(&mut self) -> Result<()> {
         self.read_line_str()?;
         Ok(())
@@ -231,7 +1909,7 @@ impl SKSTACK {
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        info!("< {}", {
+        trace!("< {}", {
             if let Ok(str) = std::str::from_utf8(buf) {
                 str.to_string()
             } else {
@@ -242,8 +1920,81 @@ impl SKSTACK {
         Ok(len)
     }
 
+    /// Sends a command whose last argument is a secret, keeping the secret
+    /// out of the log and the echoed line
+    fn write_secret_command(&mut self, command: &str, secret: &Secret) -> Result<()> {
+        trace!("< {} ***", command);
+        // Sized upfront so that no partial copy is left behind by reallocation
+        let mut line = Secret(String::with_capacity(
+            command.len() + secret.expose().len() + 3,
+        ));
+        line.0.push_str(command);
+        line.0.push(' ');
+        line.0.push_str(secret.expose());
+        line.0.push_str("\r\n");
+        let echo_prefix = if self.echo_back { Some(command) } else { None };
+        let port = self.reader.get_mut();
+        port.redact_next_line(echo_prefix);
+        port.write_all(line.expose().as_bytes())?;
+        if self.echo_back {
+            self.skip_secret_echo(command)?;
+            trace!("> {} ***", command);
+        }
+        self.consume_ok()
+    }
+
+    /// Skips the echo of a secret line one byte at a time, reading past the
+    /// `BufReader` once it is drained so that the secret never lands in its
+    /// buffer, which cannot be zeroed. Lines received before the echo, i.e.
+    /// not starting with `command`, are discarded.
+    fn skip_secret_echo(&mut self, command: &str) -> Result<()> {
+        let mut byte = [0; 1];
+        let mut prev = 0;
+        // Start of the current line, kept only until it matches `command`
+        // as the rest of the echo is secret
+        let mut line = vec![];
+        let mut is_echo = false;
+        let result = loop {
+            let result = if self.reader.buffer().is_empty() {
+                self.reader.get_mut().read_exact(&mut byte)
+            } else {
+                self.reader.read_exact(&mut byte)
+            };
+            if let Err(e) = result {
+                break Err(e.into());
+            }
+            if !is_echo {
+                line.push(byte[0]);
+                is_echo = line == command.as_bytes();
+            }
+            if prev == b'\r' && byte[0] == b'\n' {
+                if is_echo {
+                    break Ok(());
+                }
+                // e.g. an event that arrived before the echo
+                warn!(
+                    "discarding unexpected line before the echo back: {}",
+                    String::from_utf8_lossy(&line[..line.len() - 2])
+                );
+                line.clear();
+            }
+            prev = byte[0];
+        };
+        byte.zeroize();
+        prev.zeroize();
+        result
+    }
+
+    fn consume_echo_back(&mut self) -> Result<()> {
+        if self.echo_back {
+            self.read_line_str()?;
+        }
+        Ok(())
+    }
+
     fn consume_ok(&mut self) -> Result<()> {
         let ok = self.read_line_str()?;
+        check_fail(&ok)?;
         if ok == "OK" {
             Ok(())
         } else {
@@ -252,9 +2003,76 @@ impl SKSTACK {
     }
 
     pub fn read_event(&mut self) -> Result<SKEvent> {
-        let str = self.read_line_str()?;
+        match self.pending_events.pop_front() {
+            Some(event) => Ok(event),
+            None => match self.next_event() {
+                Err(Error::Reconnected(path)) => Ok(SKEvent::DeviceReconnected(path)),
+                result => result,
+            },
+        }
+    }
+
+    /// Reads the next event from the module, skipping the queued events not
+    /// to mistake them for the reply to a command. Fails with
+    /// `Error::Reconnected` after reconnecting, so that commands waiting for
+    /// a reply do not wait forever on the new device.
+    fn next_event(&mut self) -> Result<SKEvent> {
+        let mut event = match (self.read_next_event(), self.reconnect_policy.clone()) {
+            (Err(Error::Disconnected), Some(policy)) => {
+                warn!("device disconnected, reconnecting");
+                return Err(Error::Reconnected(self.reconnect_with_policy(&policy)?));
+            }
+            (result, _) => result?,
+        };
+        match &mut event {
+            SKEvent::ERXUDP { lport, handle, .. } => *handle = self.udp_handle(*lport),
+            SKEvent::ETCP {
+                status: TcpStatus::Connected,
+                handle,
+                ..
+            } => self.tcp_handles.push(*handle),
+            SKEvent::ETCP {
+                status: TcpStatus::Closed,
+                handle,
+                ..
+            } => self.tcp_handles.retain(|open| open != handle),
+            _ => {}
+        }
+        self.observe_keepalive(&event);
+        Ok(event)
+    }
+
+    /// Reads the next event, failing with a timeout error if it is not
+    /// completely received by `deadline`
+    pub fn read_event_until(&mut self, deadline: Instant) -> Result<SKEvent> {
+        self.with_deadline(deadline, |skstack| skstack.read_event())
+    }
+
+    fn read_next_event(&mut self) -> Result<SKEvent> {
+        let str = match self.erxudp_format {
+            ERXUDPFormat::Ascii => self.read_line_str()?,
+            ERXUDPFormat::Binary => {
+                let mut buf = vec![];
+                let is_data = read_until_crlf_or_binary_data(&mut self.reader, &mut buf)?;
+                if is_data {
+                    let header = std::str::from_utf8(&buf)?;
+                    trace!("> {}", header);
+                    return read_binary_data(&mut self.reader, header);
+                }
+                self.decode_line(buf)?
+            }
+        };
         if let Some(version) = str.strip_prefix("EVER ") {
             return Ok(SKEvent::EVER(version.to_string()));
+        } else if let Some(rest) = str.strip_prefix("EINFO ") {
+            return parse_einfo(rest);
+        } else if let Some(sender) = str.strip_prefix("EPONG ") {
+            return Ok(SKEvent::EPONG(sender.trim().parse()?));
+        } else if let Some(value) = str.strip_prefix("ESREG ") {
+            return Ok(SKEvent::ESREG(value.to_string()));
+        } else if str == "EEDSCAN" {
+            let line = self.read_line_str()?;
+            return Ok(SKEvent::EEDSCAN(parse_eedscan(&line)?));
         } else if str.starts_with("EPANDESC") {
             let mut read_field_value = || {
                 let line = self.read_line_str()?;
@@ -274,9 +2092,9 @@ impl SKSTACK {
             let channel = u8::from_str_radix(read_field_value()?.as_str(), 16)?;
             let channel_page = u8::from_str_radix(read_field_value()?.as_str(), 16)?;
             let pan_id = u16::from_str_radix(read_field_value()?.as_str(), 16)?;
-            let addr = read_field_value()?;
+            let addr = read_field_value()?.parse()?;
             let lqi = u8::from_str_radix(read_field_value()?.as_str(), 16)?;
-            let pair_id = read_field_value()?;
+            let pair_id = read_field_value()?.parse()?;
             return Ok(SKEvent::EPANDESC(SKPan {
                 channel,
                 channel_page,
@@ -293,17 +2111,21 @@ impl SKSTACK {
                 ))?,
                 16,
             )?;
-            let sender: String = components
+            let sender = components
                 .next()
                 .ok_or(Error::Decode(
                     format!("failed to get sender: {:}", rest).to_string(),
                 ))?
-                .to_string();
+                .parse()?;
             return Ok(SKEvent::EVENT { code, sender });
         } else if let Some(rest) = str.strip_prefix("ERXUDP ") {
-            return parse_erxudp(rest);
+            return parse_erxudp(rest).and_then(check_datalen);
+        } else if let Some(rest) = str.strip_prefix("ETCP ") {
+            return parse_etcp(rest);
+        } else if let Some(rest) = str.strip_prefix("ERXTCP ") {
+            return parse_erxtcp(rest).and_then(check_datalen);
         }
-        return Ok(SKEvent::Unknown(str));
+        Ok(SKEvent::Unknown(str))
     }
 
     fn read_line_str(&mut self) -> Result<String> {
@@ -314,13 +2136,22 @@ impl SKSTACK {
     fn read_line(&mut self) -> Result<Vec<u8>> {
         let mut buf = vec![];
         read_until_crlf(&mut self.reader, &mut buf)?;
+        self.trim_line(buf)
+    }
+
+    fn decode_line(&mut self, buf: Vec<u8>) -> Result<String> {
+        let bytes = self.trim_line(buf)?;
+        Ok(std::str::from_utf8(&bytes)?.to_string())
+    }
+
+    fn trim_line(&mut self, buf: Vec<u8>) -> Result<Vec<u8>> {
         if buf.len() < 2 {
             return Err(Error::Decode(
                 format!("too short line: {:?}", buf).to_string(),
             ));
         }
         let result: Vec<u8> = buf[..buf.len() - 2].into();
-        info!("> {}", {
+        trace!("> {}", {
             if let Ok(str) = std::str::from_utf8(&result) {
                 str.to_string()
             } else {
@@ -331,6 +2162,139 @@ impl SKSTACK {
     }
 }
 
+/// TCP connection opened by [`SKSTACK::tcp_connect`]. It borrows the
+/// [`SKSTACK`], and events other than the ones of this connection are
+/// discarded while reading from it.
+pub struct TcpConnection<'a> {
+    skstack: &'a mut SKSTACK,
+    peer: SKTcpHandle,
+    /// Received data not yet returned by `Read::read`
+    received: Vec<u8>,
+    closed: bool,
+}
+
+impl TcpConnection<'_> {
+    /// Maximum number of bytes sent by one SKSEND
+    pub const MAX_SEND_LEN: usize = 1232;
+
+    pub fn handle(&self) -> u8 {
+        self.peer.handle
+    }
+
+    pub fn peer(&self) -> &SKTcpHandle {
+        &self.peer
+    }
+
+    /// Sends all bytes, split into as many SKSEND as needed
+    pub fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        for chunk in bytes.chunks(Self::MAX_SEND_LEN) {
+            self.skstack.tcp_send(self.peer.handle, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for data from the peer. Returns `None` once the connection has
+    /// been closed.
+    pub fn receive(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.received.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.received)));
+        }
+        while !self.closed {
+            match self.skstack.read_event()? {
+                SKEvent::ERXTCP {
+                    sender,
+                    rport,
+                    lport,
+                    data,
+                    ..
+                } if sender == self.peer.ip_addr
+                    && rport == self.peer.rport
+                    && lport == self.peer.lport =>
+                {
+                    return Ok(Some(data));
+                }
+                SKEvent::ETCP {
+                    status: TcpStatus::Closed,
+                    handle,
+                    ..
+                } if handle == self.peer.handle => self.closed = true,
+                // The connection did not survive the reconnect
+                SKEvent::DeviceReconnected(path) => return Err(Error::Reconnected(path)),
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Closes the connection and waits for the module to confirm it
+    pub fn close(self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.skstack.tcp_close(self.peer.handle)
+    }
+}
+
+impl std::io::Read for TcpConnection<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.received.is_empty() {
+            match self.receive().map_err(into_io_error)? {
+                Some(data) => self.received = data,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.received.len());
+        buf[..len].copy_from_slice(&self.received[..len]);
+        self.received.drain(..len);
+        Ok(len)
+    }
+}
+
+impl std::io::Write for TcpConnection<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(Self::MAX_SEND_LEN);
+        self.skstack
+            .tcp_send(self.peer.handle, &buf[..len])
+            .map_err(into_io_error)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn into_io_error(error: Error) -> std::io::Error {
+    match error {
+        Error::Io(error) => error,
+        error => std::io::Error::other(error),
+    }
+}
+
+fn open_port(
+    selector: &DeviceSelector,
+    config: &SerialConfig,
+    timeout: Option<Duration>,
+) -> Result<TTYPort> {
+    let path = selector.resolve()?;
+    TTYPort::open(path.clone(), config, timeout).map_err(|error| {
+        let error: std::io::Error = error.into();
+        if tty::is_device_busy(&error) {
+            Error::DeviceBusy(path)
+        } else {
+            error.into()
+        }
+    })
+}
+
+/// Converts a `FAIL ERxx` reply into `Error::Fail`
+fn check_fail(line: &str) -> Result<()> {
+    match line.strip_prefix("FAIL ER") {
+        Some(code) => Err(Error::Fail(code.trim().parse()?)),
+        None => Ok(()),
+    }
+}
+
 /// Read until CRLF
 fn read_until_crlf<R: BufRead + ?Sized>(
     r: &mut R,
@@ -363,13 +2327,242 @@ fn read_until_crlf<R: BufRead + ?Sized>(
     }
 }
 
-fn decode_hex(s: String) -> std::result::Result<Vec<u8>, std::num::ParseIntError> {
-    (0..s.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+/// Read until CRLF, or until the beginning of the data field of an ERXUDP
+/// or ERXTCP event in binary format. Returns true if stopped before the data
+/// field.
+fn read_until_crlf_or_binary_data<R: BufRead + ?Sized>(
+    r: &mut R,
+    buf: &mut Vec<u8>,
+) -> std::result::Result<bool, std::io::Error> {
+    // The event name and the fields preceding the data are each followed by
+    // a space: 7 fields for ERXUDP and 4 for ERXTCP
+    const ERXUDP_HEADER_SPACES: usize = 8;
+    const ERXTCP_HEADER_SPACES: usize = 5;
+    let mut spaces = 0;
+    loop {
+        let byte = {
+            let available = match r.fill_buf() {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            match available.first() {
+                Some(byte) => *byte,
+                None => return Ok(false),
+            }
+        };
+        r.consume(1);
+        buf.push(byte);
+        if buf.ends_with(b"\r\n") {
+            return Ok(false);
+        }
+        if byte == b' ' {
+            let header_spaces = if buf.starts_with(b"ERXUDP ") {
+                ERXUDP_HEADER_SPACES
+            } else if buf.starts_with(b"ERXTCP ") {
+                ERXTCP_HEADER_SPACES
+            } else {
+                continue;
+            };
+            spaces += 1;
+            if spaces == header_spaces {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Read the binary data field following the given ERXUDP or ERXTCP header
+fn read_binary_data<R: BufRead + ?Sized>(r: &mut R, header: &str) -> Result<SKEvent> {
+    let mut event = if let Some(rest) = header.strip_prefix("ERXUDP ") {
+        parse_erxudp(rest)?
+    } else if let Some(rest) = header.strip_prefix("ERXTCP ") {
+        parse_erxtcp(rest)?
+    } else {
+        return Err(Error::Decode(format!(
+            "not an ERXUDP or ERXTCP header: {}",
+            header
+        )));
+    };
+    match &mut event {
+        SKEvent::ERXUDP { datalen, data, .. } | SKEvent::ERXTCP { datalen, data, .. } => {
+            let mut bytes = vec![0; *datalen as usize];
+            r.read_exact(&mut bytes)?;
+            *data = bytes;
+        }
+        _ => {}
+    }
+    let mut crlf = vec![];
+    read_until_crlf(r, &mut crlf)?;
+    if crlf != b"\r\n" {
+        return Err(Error::Decode(format!(
+            "unexpected trailing bytes after received data: {:?}",
+            crlf
+        )));
+    }
+    Ok(event)
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Error::Decode(format!("invalid hex data: {}", s)));
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|digits| Ok(u8::from_str_radix(core::str::from_utf8(digits)?, 16)?))
+        .collect()
+}
+
+/// Checks that the data field of an ERXUDP or ERXTCP event in ASCII format
+/// is as long as its datalen field
+fn check_datalen(event: SKEvent) -> Result<SKEvent> {
+    match &event {
+        SKEvent::ERXUDP { datalen, data, .. } | SKEvent::ERXTCP { datalen, data, .. }
+            if data.len() != *datalen as usize =>
+        {
+            Err(Error::Decode(format!(
+                "datalen {} does not match {} bytes of data",
+                datalen,
+                data.len()
+            )))
+        }
+        _ => Ok(event),
+    }
+}
+
+fn parse_einfo(rest: &str) -> Result<SKEvent> {
+    let mut components = rest.split_whitespace();
+    let mut next_field = |name: &str| {
+        components
+            .next()
+            .ok_or_else(|| Error::Decode(format!("failed to get {}: {:}", name, rest)))
+    };
+    let ip_addr = next_field("ip_addr")?.parse()?;
+    let addr = next_field("addr")?.parse()?;
+    let channel = u8::from_str_radix(next_field("channel")?, 16)?;
+    let pan_id = u16::from_str_radix(next_field("pan_id")?, 16)?;
+    let short_addr = u16::from_str_radix(next_field("short_addr")?, 16)?;
+    Ok(SKEvent::EINFO(SKInfo {
+        ip_addr,
+        addr,
+        channel,
+        pan_id,
+        short_addr,
+    }))
+}
+
+/// Parses the "<CHANNEL> <LQI> <CHANNEL> <LQI> ..." line following EEDSCAN
+fn parse_eedscan(line: &str) -> Result<Vec<SKEnergy>> {
+    let components: Vec<&str> = line.split_whitespace().collect();
+    components
+        .chunks(2)
+        .map(|pair| match pair {
+            [channel, lqi] => Ok(SKEnergy {
+                channel: u8::from_str_radix(channel, 16)?,
+                lqi: u8::from_str_radix(lqi, 16)?,
+            }),
+            _ => Err(Error::Decode(format!("missing LQI in EEDSCAN: {}", line))),
+        })
         .collect()
 }
 
+fn parse_neighbor(line: &str) -> Result<SKNeighbor> {
+    let mut components = line.split_whitespace();
+    let mut next_field = |name: &str| {
+        components
+            .next()
+            .ok_or_else(|| Error::Decode(format!("failed to get {}: {:}", name, line)))
+    };
+    let ip_addr = next_field("ip_addr")?.parse()?;
+    let addr = next_field("addr")?.parse()?;
+    let short_addr = u16::from_str_radix(next_field("short_addr")?, 16)?;
+    Ok(SKNeighbor {
+        ip_addr,
+        addr,
+        short_addr,
+    })
+}
+
+/// Parses EPORT lines: UDP ports, an empty line, then TCP ports (in decimal)
+fn parse_port_table(lines: &[String]) -> Result<SKPortTable> {
+    let mut sections = lines.split(|line| line.is_empty());
+    let mut parse_section = || -> Result<Vec<u16>> {
+        sections
+            .next()
+            .unwrap_or(&[])
+            .iter()
+            .map(|line| Ok(line.trim().parse::<u16>()?))
+            .collect()
+    };
+    let udp = parse_section()?;
+    let tcp = parse_section()?;
+    Ok(SKPortTable { udp, tcp })
+}
+
+fn parse_tcp_handle(line: &str) -> Result<SKTcpHandle> {
+    let mut components = line.split_whitespace();
+    let mut next_field = |name: &str| {
+        components
+            .next()
+            .ok_or_else(|| Error::Decode(format!("failed to get {}: {:}", name, line)))
+    };
+    let handle = u8::from_str_radix(next_field("handle")?, 16)?;
+    let ip_addr = next_field("ip_addr")?.parse()?;
+    let rport = u16::from_str_radix(next_field("rport")?, 16)?;
+    let lport = u16::from_str_radix(next_field("lport")?, 16)?;
+    Ok(SKTcpHandle {
+        handle,
+        ip_addr,
+        rport,
+        lport,
+    })
+}
+
+fn parse_etcp(rest: &str) -> Result<SKEvent> {
+    let mut components = rest.split_whitespace();
+    let mut next_field = |name: &str| {
+        components
+            .next()
+            .ok_or_else(|| Error::Decode(format!("failed to get {}: {:}", name, rest)))
+    };
+    let status = TcpStatus::try_from(u8::from_str_radix(next_field("status")?, 16)?)?;
+    let handle = u8::from_str_radix(next_field("handle")?, 16)?;
+    // The peer is only reported when a connection is established
+    let peer = if status == TcpStatus::Connected {
+        Some(parse_tcp_handle(
+            rest.split_once(' ').map_or("", |(_, peer)| peer),
+        )?)
+    } else {
+        None
+    };
+    Ok(SKEvent::ETCP {
+        status,
+        handle,
+        peer,
+    })
+}
+
+fn parse_erxtcp(rest: &str) -> Result<SKEvent> {
+    let mut components = rest.split_whitespace();
+    let mut next_field = |name: &str| {
+        components
+            .next()
+            .ok_or_else(|| Error::Decode(format!("failed to get {}: {:}", name, rest)))
+    };
+    let sender = next_field("sender")?.parse()?;
+    let rport = u16::from_str_radix(next_field("rport")?, 16)?;
+    let lport = u16::from_str_radix(next_field("lport")?, 16)?;
+    let datalen = u16::from_str_radix(next_field("datalen")?, 16)?;
+    let data = decode_hex(&components.collect::<Vec<&str>>().join(""))?;
+    Ok(SKEvent::ERXTCP {
+        sender,
+        rport,
+        lport,
+        datalen,
+        data,
+    })
+}
+
 fn parse_erxudp(rest: &str) -> Result<SKEvent> {
     let mut components = rest.split_whitespace();
     let sender = components
@@ -377,13 +2570,13 @@ fn parse_erxudp(rest: &str) -> Result<SKEvent> {
         .ok_or(Error::Decode(
             format!("failed to get sender: {:}", rest).to_string(),
         ))?
-        .to_string();
+        .parse()?;
     let dest = components
         .next()
         .ok_or(Error::Decode(
             format!("failed to get dest: {:}", rest).to_string(),
         ))?
-        .to_string();
+        .parse()?;
     let rport = u16::from_str_radix(
         components.next().ok_or(Error::Decode(
             format!("failed to get rport: {:}", rest).to_string(),
@@ -401,7 +2594,7 @@ fn parse_erxudp(rest: &str) -> Result<SKEvent> {
         .ok_or(Error::Decode(
             format!("failed to get sender_lla: {:}", rest).to_string(),
         ))?
-        .to_string();
+        .parse()?;
     let secured = u8::from_str_radix(
         components.next().ok_or(Error::Decode(
             format!("failed to get secured: {:}", rest).to_string(),
@@ -414,22 +2607,36 @@ fn parse_erxudp(rest: &str) -> Result<SKEvent> {
         ))?,
         16,
     )?;
-    let data = decode_hex(components.collect::<Vec<&str>>().join(" "))?;
-    return Ok(SKEvent::ERXUDP {
+    let data = decode_hex(&components.collect::<Vec<&str>>().join(""))?;
+    Ok(SKEvent::ERXUDP {
         sender,
         dest,
         rport,
         lport,
+        handle: None,
         sender_lla,
         secured,
         datalen,
-        data: data,
-    });
+        data,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_erxudp, read_until_crlf, Result};
+    use super::{
+        check_fail, format_ipv6_addr, pairing_id, parse_eedscan, parse_einfo, parse_erxtcp,
+        parse_erxudp, parse_etcp, parse_port_table, rank_pans, read_binary_data, read_until_crlf,
+        read_until_crlf_or_binary_data, ChannelMask, Error, Keepalive, Key128, MacAddr64,
+        PairingId, ReconnectPolicy, Register, RegisterValue, Replayer, Result, RouteBId,
+        RouteBPassword, SKEvent, SKPan, ScanDuration, Secret, TcpStatus, UdpHandle, SKSTACK,
+    };
+    use nix::fcntl::OFlag;
+    use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+    use std::io::{Read, Write};
+    use std::net::Ipv6Addr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn test_read_line_zero() -> Result<()> {
@@ -456,4 +2663,629 @@ mod tests {
         parse_erxudp(rest)?;
         Ok(())
     }
+
+    #[test]
+    fn test_link_local_addr() -> Result<()> {
+        // sender and sender_lla reported together by a real module
+        let rest = "FE80:0000:0000:0000:0280:8700:3015:29FC FE80:0000:0000:0000:1207:23FF:FEA0:75B3 0E1A 0E1A 00808700301529FC 1 0012 1081412202880105FF017201E704000001C0";
+        match parse_erxudp(rest)? {
+            SKEvent::ERXUDP {
+                sender, sender_lla, ..
+            } => {
+                assert_eq!(sender_lla.link_local_addr(), sender);
+                assert_eq!(MacAddr64::from_link_local_addr(&sender), Some(sender_lla));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert_eq!(
+            MacAddr64::from_link_local_addr(&"2001:db8::1".parse().unwrap()),
+            None
+        );
+        assert!(matches!(check_fail("FAIL ER06"), Err(Error::Fail(6))));
+        assert!(check_fail("OK").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_erxudp_binary() -> Result<()> {
+        let mut contents = b"ERXUDP FE80:0000:0000:0000:0280:8700:3015:29FC FE80:0000:0000:0000:1207:23FF:FEA0:75B3 0E1A 0E1A 00808700301529FC 1 0004 ".to_vec();
+        contents.extend_from_slice(b"\x10\r\n\x81\r\nOK\r\n");
+        let mut cursor = std::io::Cursor::new(contents);
+        let mut buf = vec![];
+        assert!(read_until_crlf_or_binary_data(&mut cursor, &mut buf)?);
+        let header = std::str::from_utf8(&buf)?;
+        match read_binary_data(&mut cursor, header)? {
+            SKEvent::ERXUDP { datalen, data, .. } => {
+                assert_eq!(datalen, 4);
+                assert_eq!(data, b"\x10\r\n\x81");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        let mut buf = vec![];
+        assert!(!read_until_crlf_or_binary_data(&mut cursor, &mut buf)?);
+        assert_eq!(buf, b"OK\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_value() -> Result<()> {
+        assert_eq!(
+            Register::PanaSessionLifetime.decode("00000E10")?,
+            RegisterValue::U32(3600)
+        );
+        assert_eq!(Register::EchoBack.decode("0")?, RegisterValue::Bool(false));
+        assert_eq!(RegisterValue::U8(0x21).encode(), "21");
+        Register::Channel.validate(&RegisterValue::U8(33))?;
+        assert!(Register::Channel.validate(&RegisterValue::U8(61)).is_err());
+        assert!(Register::Channel.validate(&RegisterValue::U16(33)).is_err());
+        assert!(Register::FrameCounter
+            .validate(&RegisterValue::U32(0))
+            .is_err());
+        assert!(Register::PairingId
+            .validate(&RegisterValue::String("0123ABCG".to_string()))
+            .is_err());
+        assert!(Register::ERXUDPFormat
+            .validate(&RegisterValue::Bool(true))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_tracked_registers() -> Result<()> {
+        let transcript = "0.000000 < SKSREG SFE 0\\r\\n\n\
+                          0.001000 > SKSREG SFE 0\\r\\nOK\\r\\n\n\
+                          0.010000 < SKVER\\r\\n\n\
+                          0.011000 > EVER 1.2.10\\r\\nOK\\r\\n\n";
+        let mut skstack = SKSTACK::with_transport(Box::new(Replayer::new(transcript)?));
+        skstack.set_register(Register::EchoBack, RegisterValue::Bool(false))?;
+        assert!(!skstack.echo_back());
+        assert_eq!(skstack.version()?, "1.2.10");
+        assert!(matches!(
+            skstack.set_register_raw("sfe", "1"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            skstack.set_register_raw("SA2", "0"),
+            Err(Error::InvalidArgument(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_einfo() -> Result<()> {
+        let rest = "FE80:0000:0000:0000:021D:1290:1234:5678 001D129012345678 21 8888 FFFE";
+        match parse_einfo(rest)? {
+            SKEvent::EINFO(info) => {
+                assert_eq!(info.addr.to_string(), "001D129012345678");
+                assert_eq!(info.ip_addr, info.addr.link_local_addr());
+                assert_eq!(info.channel, 0x21);
+                assert_eq!(info.pan_id, 0x8888);
+                assert_eq!(info.short_addr, 0xFFFE);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_port_table() -> Result<()> {
+        let lines: Vec<String> = ["3610", "716", "0", "0", "0", "0", "", "0", "0", "0", "0"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        let table = parse_port_table(&lines)?;
+        assert_eq!(table.udp, vec![3610, 716, 0, 0, 0, 0]);
+        assert_eq!(table.tcp, vec![0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_eedscan() -> Result<()> {
+        let energies = parse_eedscan("21 2A 22 E6")?;
+        assert_eq!(energies.len(), 2);
+        assert_eq!(energies[1].channel, 0x22);
+        assert_eq!(energies[1].lqi, 0xE6);
+        assert!((energies[1].rssi() - -41.02).abs() < 0.01);
+        assert!(parse_eedscan("21 2A 22").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_channel_mask() -> Result<()> {
+        assert_eq!(ChannelMask::all().bits(), 0x0FFF_FFFF);
+        let mask = ChannelMask::from_channels(vec![33, 35, 60])?;
+        assert_eq!(mask.bits(), 0x0800_0005);
+        assert_eq!(mask.channels().collect::<Vec<u8>>(), vec![33, 35, 60]);
+        assert!(mask.contains(35));
+        assert!(!mask.contains(34));
+        assert!(ChannelMask::from_range(32..=40).is_err());
+        assert!(ScanDuration::new(15).is_err());
+        assert_eq!(ScanDuration::MAX.next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rank_pans() -> Result<()> {
+        let pan = |addr: &str, lqi: u8, pair_id: &str| SKPan {
+            channel: 0x21,
+            channel_page: 9,
+            pan_id: 0x8888,
+            addr: addr.parse().unwrap(),
+            lqi,
+            pair_id: pair_id.parse().unwrap(),
+        };
+        let pair_id = pairing_id("00000000000000000000001234ABCDEF")?;
+        assert_eq!(pair_id, PairingId(0x34ABCDEF));
+        let ranked = rank_pans(
+            vec![
+                pan("001D129012345678", 0x40, "34ABCDEF"),
+                pan("001D129087654321", 0x80, "0BADBEEF"),
+                pan("001D129011111111", 0x50, "34abcdef"),
+                pan("001D129012345678", 0x60, "34ABCDEF"),
+            ],
+            pair_id,
+        );
+        let addrs: Vec<(String, u8)> = ranked.iter().map(|p| (p.addr.to_string(), p.lqi)).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                ("001D129012345678".to_string(), 0x60),
+                ("001D129011111111".to_string(), 0x50)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_skips_stale_cache() -> Result<()> {
+        // Scans right away instead of joining the cached PAN
+        let transcript = "0.000000 < SKSCAN 2 FFFFFFF 4\\r\\n\n\
+                          0.001000 > SKSCAN 2 FFFFFFF 4\\r\\nFAIL ER10\\r\\n\n";
+        let mut skstack = SKSTACK::with_transport(Box::new(Replayer::new(transcript)?));
+        let cached = SKPan {
+            channel: 0x21,
+            channel_page: 9,
+            pan_id: 0x8888,
+            addr: "001D129012345678".parse()?,
+            lqi: 0xE1,
+            pair_id: "34ABCDEF".parse()?,
+        };
+        let result = skstack.connect(Some(&cached), "12345678".parse()?);
+        assert!(matches!(result, Err(Error::Fail(10))), "{:?}", result.err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pan_round_trip() -> Result<()> {
+        let pan = SKPan {
+            channel: 0x21,
+            channel_page: 9,
+            pan_id: 0x8888,
+            addr: "001D129012345678".parse()?,
+            lqi: 0xE1,
+            pair_id: "34ABCDEF".parse()?,
+        };
+        let text = pan.to_string();
+        assert!(text.starts_with("Channel:21\nChannel Page:09\nPan ID:8888\n"));
+        let parsed: SKPan = text.parse()?;
+        assert_eq!(parsed.channel, pan.channel);
+        assert_eq!(parsed.pan_id, pan.pan_id);
+        assert_eq!(parsed.addr, pan.addr);
+        assert_eq!(parsed.pair_id, pan.pair_id);
+        assert!("Channel:21\n".parse::<SKPan>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_format() -> Result<()> {
+        let mac: MacAddr64 = "00:1D:12:90:12:34:56:78".parse()?;
+        assert_eq!(mac, "001D129012345678".parse()?);
+        assert_eq!(mac.to_string(), "001D129012345678");
+        assert!("001D1290123456".parse::<MacAddr64>().is_err());
+        assert_eq!(
+            format_ipv6_addr(&mac.link_local_addr()),
+            "FE80:0000:0000:0000:021D:1290:1234:5678"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_udp_handle() -> Result<()> {
+        assert_eq!(UdpHandle::new(1)?.value(), 1);
+        assert_eq!(UdpHandle::new(6)?.index(), 5);
+        assert!(matches!(UdpHandle::new(0), Err(Error::InvalidArgument(_))));
+        assert!(matches!(UdpHandle::new(7), Err(Error::InvalidArgument(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tcp_events() -> Result<()> {
+        match parse_etcp("1 01 FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90")? {
+            SKEvent::ETCP {
+                status: TcpStatus::Connected,
+                handle: 1,
+                peer: Some(peer),
+            } => {
+                assert_eq!(peer.handle, 1);
+                assert_eq!(
+                    peer.ip_addr,
+                    "fe80::21d:1290:1234:5678".parse::<std::net::Ipv6Addr>()?
+                );
+                assert_eq!((peer.rport, peer.lport), (80, 8080));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(matches!(
+            parse_etcp("3 01")?,
+            SKEvent::ETCP {
+                status: TcpStatus::Closed,
+                handle: 1,
+                peer: None
+            }
+        ));
+        match parse_erxtcp("FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90 0005 68656C6C6F")? {
+            SKEvent::ERXTCP {
+                rport,
+                datalen,
+                data,
+                ..
+            } => {
+                assert_eq!(rport, 80);
+                assert_eq!(datalen, 5);
+                assert_eq!(data, b"hello");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(matches!(
+            parse_erxtcp("FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90 0003 68656C6"),
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(
+            parse_erxtcp("FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90 0001 +F"),
+            Err(Error::Decode(_))
+        ));
+
+        let mut contents =
+            b"ERXTCP FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90 0003 ".to_vec();
+        contents.extend_from_slice(b"a\r\n\r\n");
+        let mut cursor = std::io::Cursor::new(contents);
+        let mut buf = vec![];
+        assert!(read_until_crlf_or_binary_data(&mut cursor, &mut buf)?);
+        match read_binary_data(&mut cursor, std::str::from_utf8(&buf)?)? {
+            SKEvent::ERXTCP { data, .. } => assert_eq!(data, b"a\r\n"),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_key128() -> Result<()> {
+        let key: Key128 = "000102030405060708090A0B0C0D0eFF".parse()?;
+        assert_eq!(key.0[1], 0x01);
+        assert_eq!(key.0[15], 0xFF);
+        assert_eq!(key.encode().expose(), "000102030405060708090A0B0C0D0EFF");
+        assert!("0001".parse::<Key128>().is_err());
+        assert!("000102030405060708090A0B0C0D0EXY"
+            .parse::<Key128>()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_redaction() -> Result<()> {
+        let password = Secret::from("0123456789AB");
+        assert_eq!(password.expose(), "0123456789AB");
+        assert_eq!(format!("{:?}", password), "Secret(***)");
+        let key: Key128 = "000102030405060708090A0B0C0D0E0F".parse()?;
+        assert_eq!(format!("{:?}", key), "Key128(***)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_routeb_credentials() -> Result<()> {
+        let id: RouteBId = "0000000000000000000000000012ABCD".parse()?;
+        assert_eq!(id.pairing_id(), PairingId(0x0012ABCD));
+        assert_eq!(pairing_id("0012ABCD")?, PairingId(0x0012ABCD));
+        assert!(pairing_id("0012ABCG").is_err());
+        assert!("0000000000000000000000000012ABC"
+            .parse::<RouteBId>()
+            .is_err());
+        assert!("000000000000000000000000001GABCD"
+            .parse::<RouteBId>()
+            .is_err());
+
+        let password: RouteBPassword = "ABCDEFGH1234".parse()?;
+        assert_eq!(password.expose(), "ABCDEFGH1234");
+        assert_eq!(format!("{:?}", password), "RouteBPassword(Secret(***))");
+        assert!("ABCDEFGH123".parse::<RouteBPassword>().is_err());
+        assert!("ABCDEFGH-123".parse::<RouteBPassword>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_connect() -> Result<()> {
+        let transcript =
+            "0.000000 < SKCONNECT FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90\\r\\n\n\
+             0.001000 > SKCONNECT FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90\\r\\nOK\\r\\n\n\
+             0.100000 > ETCP 5 02\\r\\n\n\
+             0.200000 > ETCP 1 01 FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90\\r\\n\n\
+             1.000000 < SKCONNECT FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F91\\r\\n\n\
+             1.001000 > SKCONNECT FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F91\\r\\nOK\\r\\n\n\
+             1.100000 > ETCP 3 01\\r\\n\n\
+             1.200000 > ETCP 3 02\\r\\n\n\
+             2.000000 > ERXTCP FE80:0000:0000:0000:021D:1290:1234:5678 0050 1F90 0005 6869\\r\\n\n";
+        let peer: Ipv6Addr = "FE80:0000:0000:0000:021D:1290:1234:5678".parse().unwrap();
+        let mut skstack = SKSTACK::with_transport(Box::new(Replayer::new(transcript)?));
+        // Events of other connections are skipped while connecting
+        assert_eq!(skstack.tcp_connect(&peer, 80, 8080)?.handle(), 1);
+        // Connection 1 closing is not a failure to connect, but an unknown
+        // handle closing is
+        assert!(matches!(
+            skstack.tcp_connect(&peer, 80, 8081),
+            Err(Error::UnexpectedEvent(SKEvent::ETCP { handle: 2, .. }))
+        ));
+        assert!(matches!(skstack.read_event(), Err(Error::Decode(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keepalive() -> Result<()> {
+        let transcript = "0.000000 < SKPING FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\n\n\
+             0.001000 > SKPING FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\nOK\\r\\n\n\
+             0.050000 > EPONG FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\n\n\
+             1.000000 > EVENT 26 FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\n\n\
+             2.000000 < SKPING FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\n\n\
+             2.001000 > SKPING FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\nOK\\r\\n\n\
+             # the module is busy until the next command, so EPONG never comes\n\
+             9.000000 < SKVER\\r\\n\n";
+        let peer: Ipv6Addr = "FE80:0000:0000:0000:021C:6400:030C:12A4".parse().unwrap();
+        let mut skstack = SKSTACK::with_transport(Box::new(Replayer::new(transcript)?));
+        skstack.set_keepalive(Some(Keepalive {
+            ip_v6_addr: peer,
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(1),
+        }));
+
+        skstack.check_keepalive()?;
+        assert!(skstack.last_alive.is_some());
+        // Within the interval, so nothing is sent
+        skstack.check_keepalive()?;
+
+        assert!(matches!(
+            skstack.read_event()?,
+            SKEvent::EVENT { code: 0x26, .. }
+        ));
+        assert!(skstack.last_alive.is_none());
+        assert!(matches!(
+            skstack.check_keepalive(),
+            Err(Error::LinkDown(addr)) if addr == peer
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ping_keeps_other_events() -> Result<()> {
+        let transcript = "0.000000 < SKPING FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\n\n\
+             0.001000 > SKPING FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\nOK\\r\\n\n\
+             0.020000 > ERXUDP FE80:0000:0000:0000:021C:6400:030C:12A4 FE80:0000:0000:0000:1207:23FF:FEA0:75B3 0E1A 0E1A 001C6400030C12A4 1 0002 1081\\r\\n\n\
+             0.030000 > EVENT 21 FE80:0000:0000:0000:021C:6400:030C:12A4 00\\r\\n\n\
+             0.050000 > EPONG FE80:0000:0000:0000:021C:6400:030C:12A4\\r\\n\n\
+             1.000000 < SKVER\\r\\n\n";
+        let peer: Ipv6Addr = "FE80:0000:0000:0000:021C:6400:030C:12A4".parse().unwrap();
+        let mut skstack = SKSTACK::with_transport(Box::new(Replayer::new(transcript)?));
+        skstack.ping(&peer, Duration::from_secs(1))?;
+
+        match skstack.read_event()? {
+            SKEvent::ERXUDP { sender, data, .. } => {
+                assert_eq!(sender, peer);
+                assert_eq!(data, b"\x10\x81");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(matches!(
+            skstack.read_event()?,
+            SKEvent::EVENT { code: 0x21, .. }
+        ));
+        assert!(skstack.read_event().unwrap_err().is_timeout());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_transcript() -> Result<()> {
+        let transcript = "# recorded with echo back enabled\n\
+                          0.000000 < SKVER\\r\\n\n\
+                          0.004210 > SKVER\\r\\nEVER 1.2.10\\r\\nOK\\r\\n\n\
+                          0.010000 <*\n\
+                          0.013000 > SKSETPWD C\n\
+                          0.013000 >*\n\
+                          0.013000 > OK\\r\\n\n";
+        let mut skstack = SKSTACK::with_transport(Box::new(Replayer::new(transcript)?));
+        assert_eq!(skstack.version()?, "1.2.10");
+        skstack.set_password(&"ABCDEFGH1234".parse::<RouteBPassword>()?)?;
+        assert!(matches!(skstack.version(), Err(Error::Io(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconnect_during_join() -> Result<()> {
+        let open_pty = || {
+            let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+            grantpt(&master).unwrap();
+            unlockpt(&master).unwrap();
+            let path = ptsname_r(&master).unwrap();
+            (master, path)
+        };
+        // Stands in for a by-id symlink, which points to the new tty once
+        // the device is plugged in again
+        let link = std::env::temp_dir().join(format!("skstack-reconnect-{}", std::process::id()));
+        let link_path = link.to_str().unwrap().to_string();
+        let (mut unplugged, path) = open_pty();
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&path, &link)?;
+        let mut skstack = SKSTACK::open(link_path.clone(), Some(Duration::from_secs(1)))?;
+        skstack.set_reconnect_policy(Some(ReconnectPolicy {
+            interval: Duration::from_millis(10),
+            max_attempts: 10,
+        }));
+        let (master, path) = open_pty();
+        std::fs::remove_file(&link)?;
+        std::os::unix::fs::symlink(&path, &link)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let module = spawn_fake_module(master, stop.clone());
+
+        let addr = "FE80:0000:0000:0000:021C:6400:030C:12A4";
+        let unplug = std::thread::spawn(move || {
+            let mut buf = [0; 256];
+            let len = unplugged.read(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"SKJOIN"));
+            unplugged.write_all(&buf[..len]).unwrap();
+            unplugged.write_all(b"OK\r\n").unwrap();
+            // Unplugged before the PANA authentication completes, once the
+            // reply is read as closing the master discards unread input
+            std::thread::sleep(Duration::from_millis(100));
+        });
+        let result = skstack.join(&addr.parse().unwrap());
+        unplug.join().unwrap();
+        assert!(matches!(result, Err(Error::Reconnected(path)) if path == link_path));
+        assert_eq!(skstack.version()?, "1.2.10");
+
+        stop.store(true, Ordering::SeqCst);
+        drop(skstack);
+        let commands = module.join().unwrap();
+        assert_eq!(commands.first().map(String::as_str), Some("SKRESET"));
+        std::fs::remove_file(&link)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_echo_after_unsolicited_line() -> Result<()> {
+        let mut master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let path = ptsname_r(&master).unwrap();
+        let mut skstack = SKSTACK::open(path, Some(Duration::from_secs(1)))?;
+        let transcript_path =
+            std::env::temp_dir().join(format!("skstack-transcript-{}", std::process::id()));
+        skstack.record_transcript(std::fs::File::create(&transcript_path)?);
+
+        // Received before the echo, e.g. a beacon request from another device
+        master.write_all(b"EVENT 20 FE80:0000:0000:0000:021C:6400:030C:12A4 00\r\n")?;
+        let module = std::thread::spawn(move || {
+            let mut line = vec![];
+            let mut byte = [0; 1];
+            while !line.ends_with(b"\r\n") {
+                master.read_exact(&mut byte).unwrap();
+                line.push(byte[0]);
+            }
+            master.write_all(&line).unwrap();
+            master.write_all(b"OK\r\n").unwrap();
+            master
+        });
+        skstack.set_password(&"ABCDEFGH1234".parse::<RouteBPassword>()?)?;
+        skstack.stop_recording()?;
+        drop(module.join().unwrap());
+
+        let recorded = std::fs::read_to_string(&transcript_path)?;
+        std::fs::remove_file(&transcript_path)?;
+        assert!(!recorded.contains("ABCDEFGH1234"));
+        // Only the rest of the echo is redacted, not the line before it
+        assert!(recorded.contains("> SKSETPWD C\n"));
+        assert_eq!(recorded.matches(">*").count(), 1);
+        assert!(recorded.contains("> OK\\r\\n"));
+        Ok(())
+    }
+
+    /// Answers the commands sent by `init` on the master side of a pty, with
+    /// echo back enabled at first like the module after power-on. SKRESET
+    /// stands in for a power cycle and unbinds the custom UDP ports. Returns
+    /// the command lines received once `stop` is set and the slave is closed.
+    fn spawn_fake_module(
+        mut master: PtyMaster,
+        stop: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let mut echo_back = true;
+            let mut udp_ports = [3610, 716, 0, 0, 0, 0];
+            let mut commands = vec![];
+            let mut pending = vec![];
+            let mut buf = [0; 256];
+            loop {
+                let len = match master.read(&mut buf) {
+                    Ok(len) if len > 0 => len,
+                    // The slave side is closed, e.g. while reconnecting
+                    _ if stop.load(Ordering::SeqCst) => return commands,
+                    _ => {
+                        std::thread::sleep(Duration::from_millis(10));
+                        continue;
+                    }
+                };
+                pending.extend_from_slice(&buf[..len]);
+                while let Some(end) = pending.windows(2).position(|w| w == b"\r\n") {
+                    let line: Vec<u8> = pending.drain(..end + 2).collect();
+                    let command = String::from_utf8_lossy(&line[..end]).into_owned();
+                    let mut reply = if echo_back {
+                        format!("{}\r\n", command)
+                    } else {
+                        String::new()
+                    };
+                    let args: Vec<&str> = command.split_whitespace().collect();
+                    match args.as_slice() {
+                        ["SKRESET"] => udp_ports = [3610, 716, 0, 0, 0, 0],
+                        ["SKSREG", "SFE", value] => echo_back = *value == "1",
+                        ["SKUDPPORT", handle, port] => {
+                            let index = usize::from_str_radix(handle, 16).unwrap() - 1;
+                            udp_ports[index] = u16::from_str_radix(port, 16).unwrap();
+                        }
+                        _ => {}
+                    }
+                    match args.as_slice() {
+                        ["SKVER"] => reply += "EVER 1.2.10\r\nOK\r\n",
+                        ["ROPT"] => reply += "OK 01\r\n",
+                        ["SKTABLE", "E"] => {
+                            reply += "EPORT\r\n";
+                            for port in udp_ports.iter() {
+                                reply += &format!("{}\r\n", port);
+                            }
+                            reply += "\r\n0\r\n0\r\n0\r\n0\r\nOK\r\n";
+                        }
+                        _ => reply += "OK\r\n",
+                    }
+                    master.write_all(reply.as_bytes()).unwrap();
+                    commands.push(command);
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_reconnect_same_path() -> Result<()> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let path = ptsname_r(&master).unwrap();
+        let mut skstack = SKSTACK::open(path.clone(), Some(Duration::from_secs(1)))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let module = spawn_fake_module(master, stop.clone());
+
+        skstack.init()?;
+        skstack.set_password(&"ABCDEFGH1234".parse::<RouteBPassword>()?)?;
+        skstack.open_udp_port(UdpHandle::new(3)?, 8080)?;
+        skstack.close_udp_port(UdpHandle::new(2)?)?;
+        // The old port locks the device, so it has to be closed first
+        assert_eq!(skstack.reconnect()?, path);
+        assert!(!skstack.echo_back());
+        assert_eq!(skstack.version()?, "1.2.10");
+        assert_eq!(skstack.udp_handle(8080), Some(UdpHandle::new(3)?));
+        assert_eq!(skstack.udp_handle(716), None);
+
+        stop.store(true, Ordering::SeqCst);
+        drop(skstack);
+        let commands = module.join().unwrap();
+        let count = |prefix: &str| commands.iter().filter(|c| c.starts_with(prefix)).count();
+        assert_eq!(count("SKRESET"), 2);
+        assert_eq!(count("SKSETPWD C ABCDEFGH1234"), 2);
+        assert_eq!(count("SKUDPPORT 3 1F90"), 2);
+        assert_eq!(count("SKUDPPORT 2 0000"), 2);
+        Ok(())
+    }
 }
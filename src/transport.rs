@@ -0,0 +1,659 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::tty::{self, TTYPort};
+
+/// Byte stream to the module, implemented by the serial port and by
+/// [`Replayer`]
+pub trait Transport: io::Read + io::Write + Send {
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+    fn timeout(&self) -> Option<Duration>;
+    fn set_deadline(&mut self, deadline: Option<Instant>);
+    fn deadline(&self) -> Option<Instant>;
+    /// Discards data received but not read yet
+    fn discard_input(&mut self) -> io::Result<()>;
+}
+
+impl Transport for TTYPort {
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        TTYPort::set_timeout(self, timeout)
+    }
+    fn timeout(&self) -> Option<Duration> {
+        TTYPort::timeout(self)
+    }
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        TTYPort::set_deadline(self, deadline)
+    }
+    fn deadline(&self) -> Option<Instant> {
+        TTYPort::deadline(self)
+    }
+    fn discard_input(&mut self) -> io::Result<()> {
+        TTYPort::discard_input(self).map_err(io::Error::from)
+    }
+}
+
+/// Direction of a chunk in a transcript
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    /// Written to the module, marked with `<`
+    Sent,
+    /// Read from the module, marked with `>`
+    Received,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    direction: Direction,
+    /// `None` if the line was redacted because it carries a secret
+    data: Option<Vec<u8>>,
+}
+
+/// Formats a chunk as one transcript line:
+/// `<seconds since start> <direction> <data>`, where the direction is `<`
+/// for sent and `>` for received data followed by `*` if redacted, and the
+/// data is escaped so that it fits on one line.
+fn format_entry(elapsed: Duration, entry: &Entry) -> String {
+    let mut line = format!(
+        "{}.{:06} {}",
+        elapsed.as_secs(),
+        elapsed.subsec_micros(),
+        match entry.direction {
+            Direction::Sent => '<',
+            Direction::Received => '>',
+        }
+    );
+    match &entry.data {
+        Some(data) => {
+            line.push(' ');
+            for &byte in data {
+                match byte {
+                    b'\r' => line.push_str("\\r"),
+                    b'\n' => line.push_str("\\n"),
+                    b'\\' => line.push_str("\\\\"),
+                    0x20..=0x7E => line.push(byte as char),
+                    _ => write!(line, "\\x{:02X}", byte).unwrap(),
+                }
+            }
+        }
+        None => line.push('*'),
+    }
+    line.push('\n');
+    line
+}
+
+fn parse_entry(line: &str) -> io::Result<Entry> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid transcript line: {}", line),
+        )
+    };
+    let (_elapsed, rest) = line.split_once(' ').ok_or_else(invalid)?;
+    let (marker, escaped) = rest.split_once(' ').unwrap_or((rest, ""));
+    let (direction, redacted) = match marker {
+        "<" => (Direction::Sent, false),
+        ">" => (Direction::Received, false),
+        "<*" => (Direction::Sent, true),
+        ">*" => (Direction::Received, true),
+        _ => return Err(invalid()),
+    };
+    if redacted {
+        return Ok(Entry {
+            direction,
+            data: None,
+        });
+    }
+    let mut data = vec![];
+    let mut chars = escaped.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            data.push(byte);
+            continue;
+        }
+        match chars.next().ok_or_else(invalid)? {
+            b'r' => data.push(b'\r'),
+            b'n' => data.push(b'\n'),
+            b'\\' => data.push(b'\\'),
+            b'x' => {
+                let hex = [
+                    chars.next().ok_or_else(invalid)?,
+                    chars.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                data.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(Entry {
+        direction,
+        data: Some(data),
+    })
+}
+
+/// Writes the traffic passing through a [`Port`] as a transcript
+struct Recorder {
+    sink: Box<dyn io::Write + Send>,
+    start: Instant,
+}
+
+impl Recorder {
+    fn record(&mut self, direction: Direction, data: Option<&[u8]>) -> io::Result<()> {
+        let entry = Entry {
+            direction,
+            data: data.map(<[u8]>::to_vec),
+        };
+        self.sink
+            .write_all(format_entry(self.start.elapsed(), &entry).as_bytes())
+    }
+}
+
+/// Progress through a line kept out of the transcript, which may arrive in
+/// several chunks
+#[derive(Clone, Copy, Default)]
+struct Redaction {
+    /// The redacted entry has been recorded
+    recorded: bool,
+    /// The last chunk ended with CR, so a leading LF ends the line
+    after_cr: bool,
+}
+
+/// Echo back of a redacted line still to be found among the received lines,
+/// as unsolicited lines may arrive before it
+struct PendingEcho {
+    /// Start of the echoed line, which is not secret
+    prefix: Vec<u8>,
+    /// Start of the current line held back until it is known whether it is
+    /// the echo
+    held: Vec<u8>,
+    /// The next byte starts a line
+    line_start: bool,
+}
+
+/// Transport used by `SKSTACK`, which can record the traffic passing through
+pub(crate) struct Port {
+    transport: Box<dyn Transport>,
+    recorder: Option<Recorder>,
+    /// Record the data up to the next CRLF in each direction as redacted
+    redact_sent: Option<Redaction>,
+    redact_received: Option<Redaction>,
+    pending_echo: Option<PendingEcho>,
+}
+
+impl Port {
+    pub(crate) fn new(transport: Box<dyn Transport>) -> Self {
+        Port {
+            transport,
+            recorder: None,
+            redact_sent: None,
+            redact_received: None,
+            pending_echo: None,
+        }
+    }
+
+    pub(crate) fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    pub(crate) fn transport_mut(&mut self) -> &mut dyn Transport {
+        self.transport.as_mut()
+    }
+
+    /// Swaps the underlying transport, keeping the recording going
+    pub(crate) fn replace_transport(&mut self, transport: Box<dyn Transport>) {
+        self.transport = transport;
+        self.redact_sent = None;
+        self.redact_received = None;
+        self.pending_echo = None;
+    }
+
+    /// Drops the underlying transport, releasing the device so that it can
+    /// be opened again. I/O fails as disconnected until it is replaced.
+    pub(crate) fn close_transport(&mut self) {
+        let timeout = self.transport.timeout();
+        self.replace_transport(Box::new(Closed {
+            timeout,
+            deadline: None,
+        }));
+    }
+
+    pub(crate) fn start_recording(&mut self, sink: Box<dyn io::Write + Send>) {
+        self.recorder = Some(Recorder {
+            sink,
+            start: Instant::now(),
+        });
+    }
+
+    pub(crate) fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(mut recorder) => recorder.sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps the next line sent out of the transcript, and its echo back
+    /// after `echo_prefix` if given. Received lines not starting with the
+    /// prefix are recorded as usual.
+    pub(crate) fn redact_next_line(&mut self, echo_prefix: Option<&str>) {
+        self.redact_sent = Some(Redaction::default());
+        self.redact_received = None;
+        self.pending_echo = echo_prefix.map(|prefix| PendingEcho {
+            prefix: prefix.as_bytes().to_vec(),
+            held: vec![],
+            line_start: true,
+        });
+    }
+
+    /// Records a chunk, splitting off the redacted part up to the first CRLF.
+    /// A failing sink stops the recording rather than the session, as the
+    /// data has already been exchanged with the module.
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        if let Err(e) = self.record_entries(direction, data) {
+            warn!("stopped recording the transcript: {}", e);
+            self.recorder = None;
+        }
+    }
+
+    fn record_entries(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let data = match direction {
+            Direction::Sent => data,
+            Direction::Received => &data[self.record_until_echo(data)?..],
+        };
+        let redact = match direction {
+            Direction::Sent => &mut self.redact_sent,
+            Direction::Received => &mut self.redact_received,
+        };
+        let mut rest = data;
+        if let (Some(redaction), false) = (*redact, data.is_empty()) {
+            let end = if redaction.after_cr && data[0] == b'\n' {
+                Some(1)
+            } else {
+                data.windows(2).position(|w| w == b"\r\n").map(|i| i + 2)
+            };
+            *redact = match end {
+                Some(_) => None,
+                None => Some(Redaction {
+                    recorded: true,
+                    after_cr: data.ends_with(b"\r"),
+                }),
+            };
+            rest = &data[end.unwrap_or(data.len())..];
+            if let (false, Some(recorder)) = (redaction.recorded, &mut self.recorder) {
+                recorder.record(direction, None)?;
+            }
+        }
+        match &mut self.recorder {
+            Some(recorder) if !rest.is_empty() => recorder.record(direction, Some(rest)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the received lines preceding the pending echo back, and
+    /// starts redacting once its prefix is found. Returns the length of the
+    /// data handled, the rest being recorded as usual.
+    fn record_until_echo(&mut self, data: &[u8]) -> io::Result<usize> {
+        let echo = match &mut self.pending_echo {
+            Some(echo) => echo,
+            None => return Ok(0),
+        };
+        // Where the bytes held back from this chunk start
+        let mut hold_start = 0;
+        for (i, &byte) in data.iter().enumerate() {
+            if !echo.line_start {
+                echo.line_start = byte == b'\n';
+                continue;
+            }
+            if echo.held.is_empty() {
+                hold_start = i;
+            }
+            echo.held.push(byte);
+            if echo.held == echo.prefix {
+                if let Some(recorder) = &mut self.recorder {
+                    if hold_start > 0 {
+                        recorder.record(Direction::Received, Some(&data[..hold_start]))?;
+                    }
+                    recorder.record(Direction::Received, Some(&echo.held))?;
+                }
+                self.pending_echo = None;
+                self.redact_received = Some(Redaction::default());
+                return Ok(i + 1);
+            }
+            if !echo.prefix.starts_with(&echo.held) {
+                // Held back from the previous chunks, the rest is recorded
+                // along with this chunk
+                let carried = echo.held.len() - (i + 1 - hold_start);
+                if let (true, Some(recorder)) = (carried > 0, &mut self.recorder) {
+                    recorder.record(Direction::Received, Some(&echo.held[..carried]))?;
+                }
+                echo.held.clear();
+                echo.line_start = byte == b'\n';
+            }
+        }
+        if echo.held.is_empty() {
+            return Ok(0);
+        }
+        if let (true, Some(recorder)) = (hold_start > 0, &mut self.recorder) {
+            recorder.record(Direction::Received, Some(&data[..hold_start]))?;
+        }
+        Ok(data.len())
+    }
+}
+
+impl io::Read for Port {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.transport.read(buf)?;
+        self.record(Direction::Received, &buf[..len]);
+        Ok(len)
+    }
+}
+
+impl io::Write for Port {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.transport.write(buf)?;
+        self.record(Direction::Sent, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()?;
+        if let Some(Err(e)) = self.recorder.as_mut().map(|recorder| recorder.sink.flush()) {
+            warn!("stopped recording the transcript: {}", e);
+            self.recorder = None;
+        }
+        Ok(())
+    }
+}
+
+/// Stands in for a transport that has been closed
+struct Closed {
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl io::Read for Closed {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(tty::disconnected_error().into())
+    }
+}
+
+impl io::Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(tty::disconnected_error().into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Closed {
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    fn discard_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fake transport playing back a transcript recorded with
+/// `SKSTACK::record_transcript`. Received chunks are returned as soon as all
+/// the data sent before them in the transcript has been written, ignoring
+/// the timestamps. Writing anything else fails with `InvalidData`, and
+/// reading past the end of the transcript reports a disconnect.
+pub struct Replayer {
+    entries: VecDeque<Entry>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Replayer {
+    pub fn new(transcript: &str) -> io::Result<Self> {
+        let entries = transcript
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(parse_entry)
+            .collect::<io::Result<_>>()?;
+        Ok(Replayer {
+            entries,
+            timeout: None,
+            deadline: None,
+        })
+    }
+
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        Self::new(&std::fs::read_to_string(path)?)
+    }
+
+    /// Whether the whole transcript has been played back
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl io::Read for Replayer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let entry = match self.entries.front_mut() {
+            Some(entry) => entry,
+            None => return Err(tty::disconnected_error().into()),
+        };
+        if entry.direction == Direction::Sent {
+            // The module stays silent until it receives the next command
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "transcript expects data to be sent first",
+            ));
+        }
+        // The contents of a redacted line are not needed as long as the
+        // reader discards it like the secret echoed back
+        let data = entry.data.get_or_insert_with(|| b"***\r\n".to_vec());
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        data.drain(..len);
+        if data.is_empty() {
+            self.entries.pop_front();
+        }
+        Ok(len)
+    }
+}
+
+impl io::Write for Replayer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let unexpected = |expected: Option<&[u8]>| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unexpected data sent: {:?}, transcript expects {:?}",
+                    String::from_utf8_lossy(buf),
+                    expected.map(String::from_utf8_lossy)
+                ),
+            )
+        };
+        let entry = match self.entries.front_mut() {
+            Some(entry) if entry.direction == Direction::Sent => entry,
+            Some(entry) => return Err(unexpected(entry.data.as_deref())),
+            None => return Err(unexpected(None)),
+        };
+        let len = match &mut entry.data {
+            // A redacted line matches anything up to the next CRLF
+            None => match buf.windows(2).position(|w| w == b"\r\n") {
+                Some(end) => {
+                    self.entries.pop_front();
+                    end + 2
+                }
+                None => buf.len(),
+            },
+            Some(data) => {
+                let len = buf.len().min(data.len());
+                if buf[..len] != data[..len] {
+                    return Err(unexpected(Some(data)));
+                }
+                data.drain(..len);
+                if data.is_empty() {
+                    self.entries.pop_front();
+                }
+                len
+            }
+        };
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replayer {
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    fn discard_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_entry, parse_entry, Direction, Entry, Port, Replayer};
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_entry_round_trip() -> std::io::Result<()> {
+        let entry = Entry {
+            direction: Direction::Received,
+            data: Some(b"ERXUDP \x10\x81\\ \r\n".to_vec()),
+        };
+        let line = format_entry(Duration::from_micros(1_500_042), &entry);
+        assert_eq!(line, "1.500042 > ERXUDP \\x10\\x81\\\\ \\r\\n\n");
+        assert_eq!(parse_entry(line.trim_end_matches('\n'))?, entry);
+        assert_eq!(
+            parse_entry("0.000100 <*")?,
+            Entry {
+                direction: Direction::Sent,
+                data: None
+            }
+        );
+        assert!(parse_entry("0.000100 ? SKVER").is_err());
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failing_sink_keeps_session() -> std::io::Result<()> {
+        let transcript = "0.000000 < SKVER\\r\\n\n\
+                          0.010000 > EVER 1.2.10\\r\\nOK\\r\\n\n";
+        let mut port = Port::new(Box::new(Replayer::new(transcript)?));
+        port.start_recording(Box::new(FailingSink));
+        port.write_all(b"SKVER\r\n")?;
+        assert!(port.recorder.is_none());
+        let mut buf = [0; 64];
+        let len = port.read(&mut buf)?;
+        assert_eq!(&buf[..len], b"EVER 1.2.10\r\nOK\r\n");
+        port.flush()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_and_replay() -> std::io::Result<()> {
+        let transcript = "0.000000 < SKVER\\r\\n\n\
+                          0.010000 > SKVER\\r\\nEVER 1.2.10\\r\\nOK\\r\\n\n\
+                          0.020000 <*\n\
+                          0.025000 > EVENT 21 FE80:0000:0000:0000:021C:6400:030C:12A4 00\\r\\nSKSETPWD C\n\
+                          0.030000 >*\n\
+                          0.030000 > OK\\r\\n\n";
+        let mut replayer = Replayer::new(transcript)?;
+        assert!(replayer.write_all(b"SKINFO\r\n").is_err());
+
+        let sink = SharedBuffer::default();
+        let mut port = Port::new(Box::new(Replayer::new(transcript)?));
+        port.start_recording(Box::new(sink.clone()));
+        let mut buf = [0; 64];
+        assert_eq!(
+            port.read(&mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::TimedOut
+        );
+        port.write_all(b"SKVER\r\n")?;
+        let len = port.read(&mut buf)?;
+        assert_eq!(&buf[..len], b"SKVER\r\nEVER 1.2.10\r\nOK\r\n");
+        port.redact_next_line(Some("SKSETPWD C"));
+        port.write_all(b"SKSETPWD C 0123456789AB\r\n")?;
+        // Recorded as usual up to the echoed command
+        let len = port.read(&mut buf)?;
+        assert_eq!(
+            &buf[..len],
+            b"EVENT 21 FE80:0000:0000:0000:021C:6400:030C:12A4 00\r\nSKSETPWD C"
+        );
+        // The rest of the echo is read one byte at a time, splitting the CRLF
+        let mut echo = [0; 5];
+        for byte in echo.chunks_mut(1) {
+            assert_eq!(port.read(byte)?, 1);
+        }
+        assert_eq!(&echo, b"***\r\n");
+        let len = port.read(&mut buf)?;
+        assert_eq!(&buf[..len], b"OK\r\n");
+        assert!(port.read(&mut buf).is_err());
+        port.stop_recording()?;
+
+        // The recording can be played back in turn
+        let recorded = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert!(!recorded.contains("0123456789AB"));
+        assert_eq!(recorded.matches(">*").count(), 1);
+        assert!(recorded.contains("> EVENT 21 "));
+        assert!(recorded.contains("> OK\\r\\n"));
+        let mut replayer = Replayer::new(&recorded)?;
+        replayer.write_all(b"SKVER\r\n")?;
+        assert_eq!(replayer.read(&mut buf)?, 24);
+        replayer.write_all(b"SKSETPWD C 0000\r\n")?;
+        assert_eq!(replayer.read(&mut buf)?, 53);
+        assert_eq!(replayer.read(&mut buf)?, 10);
+        assert_eq!(replayer.read(&mut buf)?, 5);
+        assert_eq!(replayer.read(&mut buf)?, 4);
+        assert!(replayer.is_finished());
+        Ok(())
+    }
+}
@@ -1,11 +1,10 @@
 use std::io;
 use std::mem::MaybeUninit;
 use std::os::unix::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::slice;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use libc::{cfsetspeed, speed_t};
 use nix::fcntl::OFlag;
 use nix::poll::{PollFd, PollFlags};
 use nix::{self, libc, unistd};
@@ -18,18 +17,124 @@ fn close(fd: RawFd) {
 pub struct TTYPort {
     fd: RawFd,
     port_name: Option<String>,
-    baud_rate: u32,
+    config: SerialConfig,
     timeout: Option<Duration>,
+    /// Bounds the total time of reads and writes regardless of `timeout`
+    deadline: Option<Instant>,
 }
 
+/// Number of data bits per character
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    /// RTS/CTS
+    Hardware,
+}
+
+/// Line settings of the serial port. Defaults to 115200 baud, 8N1 and no
+/// flow control, which is the factory setting of most SKSTACK modules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialConfig {
+    /// Any rate supported by the UART. Non-standard rates are supported on
+    /// Linux only.
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl SerialConfig {
+    pub fn new(baud_rate: u32) -> Self {
+        SerialConfig {
+            baud_rate,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            baud_rate: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Error(io::Error);
 
+/// Reported when the device is already opened by another process
+#[derive(Debug)]
+pub struct DeviceBusy;
+
+impl std::fmt::Display for DeviceBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("device is busy")
+    }
+}
+
+impl std::error::Error for DeviceBusy {}
+
+/// Reported when the device has hung up, e.g. after a USB unplug
+#[derive(Debug)]
+pub struct Disconnected;
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("device disconnected")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+fn busy_error() -> Error {
+    Error(io::Error::other(DeviceBusy))
+}
+
+pub(crate) fn disconnected_error() -> Error {
+    Error(io::Error::new(io::ErrorKind::BrokenPipe, Disconnected))
+}
+
+pub fn is_device_busy(error: &io::Error) -> bool {
+    matches!(error.get_ref(), Some(inner) if inner.is::<DeviceBusy>())
+}
+
+pub fn is_disconnected(error: &io::Error) -> bool {
+    matches!(error.get_ref(), Some(inner) if inner.is::<Disconnected>())
+}
+
 impl From<nix::Error> for Error {
     fn from(err: nix::Error) -> Self {
         match err {
             nix::Error::InvalidPath => Error(io::Error::new(io::ErrorKind::InvalidInput, err)),
-            nix::Error::InvalidUtf8 => Error(io::Error::new(io::ErrorKind::Other, err)),
-            nix::Error::UnsupportedOperation => Error(io::Error::new(io::ErrorKind::Other, err)),
+            nix::Error::InvalidUtf8 => Error(io::Error::other(err)),
+            nix::Error::UnsupportedOperation => Error(io::Error::other(err)),
             nix::Error::Sys(errno) => Error(io::Error::from_raw_os_error(errno as i32)),
         }
     }
@@ -40,26 +145,35 @@ impl From<io::Error> for Error {
         Self(err)
     }
 }
-impl Into<io::Error> for Error {
-    fn into(self) -> io::Error {
-        self.0
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        err.0
     }
 }
 
 impl TTYPort {
     pub fn open(
         path_str: String,
-        baud_rate: u32,
+        config: &SerialConfig,
         timeout: Option<Duration>,
     ) -> Result<TTYPort, Error> {
-        use nix::libc::{tcgetattr, tcsetattr};
+        use nix::libc::tcgetattr;
 
         let path = Path::new(&path_str);
         let fd = nix::fcntl::open(
             path,
             OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK,
             nix::sys::stat::Mode::empty(),
-        )?;
+        )
+        .map_err(|e| match e {
+            // Another process has put the tty in exclusive mode
+            nix::Error::Sys(nix::errno::Errno::EBUSY) => busy_error(),
+            e => e.into(),
+        })?;
+        if let Err(e) = lock_exclusive(fd) {
+            close(fd);
+            return Err(e);
+        }
 
         let mut termios = MaybeUninit::uninit();
         let res = unsafe { tcgetattr(fd, termios.as_mut_ptr()) };
@@ -69,75 +183,309 @@ impl TTYPort {
         }
         let mut termios = unsafe { termios.assume_init() };
 
-        {
-            termios.c_cflag = libc::CS8 | libc::CREAD | libc::CLOCAL | libc::HUPCL;
-            termios.c_lflag &= !(libc::ICANON
-                | libc::ECHO
-                | libc::ECHOE
-                | libc::ECHOK
-                | libc::ECHONL
-                | libc::ISIG
-                | libc::IEXTEN);
-            termios.c_oflag &= !(libc::OPOST | libc::ONLCR | libc::OCRNL);
-            termios.c_iflag &= !(libc::INLCR | libc::IGNCR | libc::ICRNL | libc::IGNBRK);
-            termios.c_cc[libc::VTIME] = 0;
-            unsafe { cfsetspeed(&mut termios, baud_rate as speed_t) };
-            unsafe { tcsetattr(fd, libc::TCSANOW, &termios) };
+        // Run in a closure so that the fd is closed on any error
+        (|| -> Result<(), Error> {
+            configure_termios(&mut termios, config);
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            {
+                let res =
+                    unsafe { libc::cfsetspeed(&mut termios, config.baud_rate as libc::speed_t) };
+                nix::errno::Errno::result(res)?;
+                let res = unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+                nix::errno::Errno::result(res)?;
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            set_termios2(fd, &termios, config.baud_rate)?;
             unsafe { libc::tcflush(fd, libc::TCIOFLUSH) };
             nix::fcntl::fcntl(fd, nix::fcntl::F_SETFL(nix::fcntl::OFlag::empty()))?;
 
             Ok(())
-        }
-        .map_err(|e: Error| {
+        })()
+        .inspect_err(|_: &Error| {
             close(fd);
-            e
         })?;
 
         Ok(TTYPort {
             fd,
             port_name: Some(path_str.clone()),
-            baud_rate: baud_rate,
-            timeout: timeout,
+            config: config.clone(),
+            timeout,
+            deadline: None,
         })
     }
     pub fn set_timeout(&mut self, timeout: Option<Duration>) {
         self.timeout = timeout;
     }
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    /// Returns how long the next poll may wait, the shorter of the timeout
+    /// and the time left until the deadline
+    fn wait_duration(&self) -> Result<Option<Duration>, Error> {
+        let remaining = match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => Some(remaining),
+                None => return Err(timed_out_error()),
+            },
+            None => None,
+        };
+        Ok(match (self.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        })
+    }
+    /// Discards data received but not read yet
+    pub fn discard_input(&mut self) -> Result<(), Error> {
+        nix::sys::termios::tcflush(self.fd, nix::sys::termios::FlushArg::TCIFLUSH)?;
+        Ok(())
+    }
+    pub fn port_name(&self) -> Option<&str> {
+        self.port_name.as_deref()
+    }
+    pub fn config(&self) -> &SerialConfig {
+        &self.config
+    }
+}
+
+/// Identifies the serial device of the module, which may be assigned a
+/// different /dev/ttyUSBn after it is re-enumerated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// Fixed device path, preferably a stable /dev/serial/by-id symlink
+    Path(String),
+    /// USB device matched by the attributes exposed in sysfs
+    Usb {
+        vendor_id: u16,
+        product_id: u16,
+        serial: Option<String>,
+    },
+}
+
+impl DeviceSelector {
+    /// Returns the device path currently matching this selector
+    pub fn resolve(&self) -> io::Result<String> {
+        match self {
+            DeviceSelector::Path(path) => {
+                // Follow by-id symlinks so that the error is raised here if
+                // the device is not present
+                std::fs::canonicalize(path)?;
+                Ok(path.clone())
+            }
+            DeviceSelector::Usb {
+                vendor_id,
+                product_id,
+                serial,
+            } => find_usb_tty(
+                Path::new("/sys/class/tty"),
+                *vendor_id,
+                *product_id,
+                serial.as_deref(),
+            )?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no tty found for USB device {:?}", self),
+                )
+            }),
+        }
+    }
+}
+
+/// Looks for a tty whose USB device matches the given attributes in the
+/// sysfs tty class directory
+fn find_usb_tty(
+    class_dir: &Path,
+    vendor_id: u16,
+    product_id: u16,
+    serial: Option<&str>,
+) -> io::Result<Option<String>> {
+    let mut names: Vec<String> = std::fs::read_dir(class_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    for name in names {
+        let device = match std::fs::canonicalize(class_dir.join(&name).join("device")) {
+            Ok(device) => device,
+            Err(_) => continue,
+        };
+        if let Some(usb_device) = find_usb_device(&device) {
+            let read_attr = |attr: &str| {
+                std::fs::read_to_string(usb_device.join(attr))
+                    .map(|value| value.trim().to_string())
+                    .ok()
+            };
+            let matches_id = |attr: &str, id: u16| {
+                read_attr(attr).and_then(|value| u16::from_str_radix(&value, 16).ok()) == Some(id)
+            };
+            if matches_id("idVendor", vendor_id)
+                && matches_id("idProduct", product_id)
+                && (serial.is_none() || read_attr("serial").as_deref() == serial)
+            {
+                return Ok(Some(format!("/dev/{}", name)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Walks up from a tty's device directory to the USB device owning it
+fn find_usb_device(device: &Path) -> Option<PathBuf> {
+    device
+        .ancestors()
+        .find(|dir| dir.join("idVendor").is_file())
+        .map(Path::to_path_buf)
+}
+
+/// Takes an advisory lock and puts the tty in exclusive mode so that other
+/// processes cannot open it and interleave reads.
+fn lock_exclusive(fd: RawFd) -> Result<(), Error> {
+    use nix::fcntl::{flock, FlockArg};
+
+    flock(fd, FlockArg::LockExclusiveNonblock).map_err(|e| match e {
+        nix::Error::Sys(nix::errno::Errno::EAGAIN) => busy_error(),
+        e => e.into(),
+    })?;
+    let res = unsafe { libc::ioctl(fd, libc::TIOCEXCL) };
+    nix::errno::Errno::result(res)?;
+    Ok(())
+}
+
+/// Sets the framing and flow control flags and puts the line in raw mode.
+/// The baud rate is set separately.
+fn configure_termios(termios: &mut libc::termios, config: &SerialConfig) {
+    termios.c_cflag = libc::CREAD | libc::CLOCAL | libc::HUPCL;
+    termios.c_cflag |= match config.data_bits {
+        DataBits::Five => libc::CS5,
+        DataBits::Six => libc::CS6,
+        DataBits::Seven => libc::CS7,
+        DataBits::Eight => libc::CS8,
+    };
+    match config.parity {
+        Parity::None => termios.c_iflag &= !libc::INPCK,
+        Parity::Odd => {
+            termios.c_cflag |= libc::PARENB | libc::PARODD;
+            termios.c_iflag |= libc::INPCK;
+        }
+        Parity::Even => {
+            termios.c_cflag |= libc::PARENB;
+            termios.c_iflag |= libc::INPCK;
+        }
+    }
+    if config.stop_bits == StopBits::Two {
+        termios.c_cflag |= libc::CSTOPB;
+    }
+    if config.flow_control == FlowControl::Hardware {
+        termios.c_cflag |= libc::CRTSCTS;
+    }
+    termios.c_lflag &= !(libc::ICANON
+        | libc::ECHO
+        | libc::ECHOE
+        | libc::ECHOK
+        | libc::ECHONL
+        | libc::ISIG
+        | libc::IEXTEN);
+    termios.c_oflag &= !(libc::OPOST | libc::ONLCR | libc::OCRNL);
+    termios.c_iflag &= !(libc::INLCR
+        | libc::IGNCR
+        | libc::ICRNL
+        | libc::IGNBRK
+        | libc::IXON
+        | libc::IXOFF
+        | libc::IXANY);
+    // Block until at least one byte is available
+    termios.c_cc[libc::VMIN] = 1;
+    termios.c_cc[libc::VTIME] = 0;
+}
+
+/// Applies the termios with an arbitrary baud rate through termios2, which
+/// is not limited to the standard rates accepted by cfsetspeed.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_termios2(fd: RawFd, termios: &libc::termios, baud_rate: u32) -> Result<(), Error> {
+    // Apply the flags and the rate in a single call, as c_cflag has no valid
+    // Bxxx bits and applying it alone would hang up the line with B0
+    let mut termios2 = MaybeUninit::<libc::termios2>::uninit();
+    let res = unsafe { libc::ioctl(fd, libc::TCGETS2, termios2.as_mut_ptr()) };
+    nix::errno::Errno::result(res)?;
+    let mut termios2 = unsafe { termios2.assume_init() };
+    termios2.c_iflag = termios.c_iflag;
+    termios2.c_oflag = termios.c_oflag;
+    termios2.c_cflag = termios.c_cflag;
+    termios2.c_lflag = termios.c_lflag;
+    termios2.c_line = termios.c_line;
+    let cc_len = termios2.c_cc.len();
+    termios2.c_cc.copy_from_slice(&termios.c_cc[..cc_len]);
+    termios2.c_cflag &= !libc::CBAUD;
+    termios2.c_cflag |= libc::BOTHER;
+    termios2.c_ispeed = baud_rate;
+    termios2.c_ospeed = baud_rate;
+    let res = unsafe { libc::ioctl(fd, libc::TCSETS2, &termios2) };
+    nix::errno::Errno::result(res)?;
+    Ok(())
 }
 
 impl Drop for TTYPort {
     fn drop(&mut self) {
+        unsafe { libc::ioctl(self.fd, libc::TIOCNXCL) };
         close(self.fd);
     }
 }
 
 impl io::Read for TTYPort {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if let Some(timeout) = self.timeout {
+        let timeout = self.wait_duration()?;
+        if let Some(timeout) = timeout {
             if let Err(e) = wait_read_fd(self.fd, timeout) {
-                return Err(Error::from(e).into());
+                return Err(e.into());
             }
         }
-        nix::unistd::read(self.fd, buf).map_err(|e| Error::from(e).into())
+        match nix::unistd::read(self.fd, buf) {
+            // Either poll reported the fd as readable or the read blocked
+            // until at least VMIN bytes arrived, so EOF means the tty hung up
+            Ok(0) if !buf.is_empty() => Err(disconnected_error().into()),
+            Ok(n) => Ok(n),
+            Err(e) => Err(map_disconnect(e).into()),
+        }
     }
 }
 
 impl io::Write for TTYPort {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if let Some(timeout) = self.timeout {
+        if let Some(timeout) = self.wait_duration()? {
             if let Err(e) = wait_write_fd(self.fd, timeout) {
                 return Err(e.into());
             }
         }
-        nix::unistd::write(self.fd, buf).map_err(|e| Error::from(e).into())
+        nix::unistd::write(self.fd, buf).map_err(|e| map_disconnect(e).into())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        nix::sys::termios::tcdrain(self.fd)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "flush failed"))
+        nix::sys::termios::tcdrain(self.fd).map_err(|_| io::Error::other("flush failed"))
     }
 }
 
+/// Maps errno values raised by a vanished device to `Disconnected`
+fn map_disconnect(err: nix::Error) -> Error {
+    use nix::errno::Errno::{EIO, ENODEV, ENXIO};
+
+    match err {
+        nix::Error::Sys(EIO) | nix::Error::Sys(ENXIO) | nix::Error::Sys(ENODEV) => {
+            disconnected_error()
+        }
+        err => err.into(),
+    }
+}
+
+fn timed_out_error() -> Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Operation timed out").into()
+}
+
 fn wait_read_fd(fd: RawFd, timeout: Duration) -> Result<(), Error> {
     wait_fd(fd, PollFlags::POLLIN, timeout)
 }
@@ -160,16 +508,187 @@ fn wait_fd(fd: RawFd, events: PollFlags, timeout: Duration) -> Result<(), Error>
         Err(e) => return Err(Error::from(e)),
     };
     if wait != 1 {
-        return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out").into());
+        return Err(timed_out_error());
     }
 
     match fd.revents() {
         Some(e) if e == events => return Ok(()),
-        Some(e) if e.contains(PollFlags::POLLHUP) || e.contains(PollFlags::POLLNVAL) => {
+        Some(e) if e.contains(PollFlags::POLLHUP) || e.contains(PollFlags::POLLERR) => {
+            return Err(disconnected_error());
+        }
+        Some(e) if e.contains(PollFlags::POLLNVAL) => {
             return Err(io::Error::new(io::ErrorKind::BrokenPipe, EPIPE.desc()).into());
         }
         Some(_) | None => (),
     }
 
-    Err(io::Error::new(io::ErrorKind::Other, EIO.desc()).into())
+    Err(io::Error::other(EIO.desc()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        configure_termios, find_usb_tty, is_device_busy, is_disconnected, DataBits, FlowControl,
+        Parity, SerialConfig, StopBits, TTYPort,
+    };
+    use nix::fcntl::OFlag;
+    use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+    use std::io::{BufRead, BufReader, Read};
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_open_exclusive() {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let path = ptsname_r(&master).unwrap();
+
+        let config = SerialConfig::default();
+        let port = TTYPort::open(path.clone(), &config, None).unwrap();
+        let error: std::io::Error = TTYPort::open(path.clone(), &config, None)
+            .unwrap_err()
+            .into();
+        assert!(is_device_busy(&error));
+
+        drop(port);
+        TTYPort::open(path, &config, None).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_open_applies_config() {
+        use nix::libc;
+
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let path = ptsname_r(&master).unwrap();
+        let config = SerialConfig {
+            baud_rate: 250_000,
+            data_bits: DataBits::Seven,
+            parity: Parity::Odd,
+            stop_bits: StopBits::Two,
+            flow_control: FlowControl::Hardware,
+        };
+        let port = TTYPort::open(path, &config, None).unwrap();
+
+        let mut termios2 = std::mem::MaybeUninit::<libc::termios2>::uninit();
+        let res = unsafe { libc::ioctl(port.fd, libc::TCGETS2, termios2.as_mut_ptr()) };
+        assert_eq!(res, 0);
+        let termios2 = unsafe { termios2.assume_init() };
+        assert_eq!(termios2.c_cflag & libc::CBAUD, libc::BOTHER);
+        assert_eq!(termios2.c_ospeed, 250_000);
+        assert_eq!(termios2.c_ispeed, 250_000);
+        // The pty itself forces 8 data bits without parity
+        assert_ne!(termios2.c_cflag & libc::CSTOPB, 0);
+        assert_ne!(termios2.c_cflag & libc::CRTSCTS, 0);
+        assert_eq!(termios2.c_lflag & libc::ICANON, 0);
+    }
+
+    #[test]
+    fn test_configure_termios() {
+        use nix::libc;
+
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        termios.c_lflag = libc::ICANON | libc::ECHO;
+        configure_termios(
+            &mut termios,
+            &SerialConfig {
+                baud_rate: 250_000,
+                data_bits: DataBits::Seven,
+                parity: Parity::Odd,
+                stop_bits: StopBits::Two,
+                flow_control: FlowControl::Hardware,
+            },
+        );
+        assert_eq!(termios.c_cflag & libc::CSIZE, libc::CS7);
+        assert_eq!(
+            termios.c_cflag & (libc::PARENB | libc::PARODD),
+            libc::PARENB | libc::PARODD
+        );
+        assert_ne!(termios.c_iflag & libc::INPCK, 0);
+        assert_ne!(termios.c_cflag & libc::CSTOPB, 0);
+        assert_ne!(termios.c_cflag & libc::CRTSCTS, 0);
+        assert_eq!(termios.c_lflag & (libc::ICANON | libc::ECHO), 0);
+
+        configure_termios(&mut termios, &SerialConfig::new(9600));
+        assert_eq!(termios.c_cflag & libc::CSIZE, libc::CS8);
+        assert_eq!(
+            termios.c_cflag & (libc::PARENB | libc::CSTOPB | libc::CRTSCTS),
+            0
+        );
+        assert_eq!(termios.c_iflag & libc::INPCK, 0);
+    }
+
+    #[test]
+    fn test_hang_up_without_timeout() {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let path = ptsname_r(&master).unwrap();
+        let mut port = TTYPort::open(path, &SerialConfig::default(), None).unwrap();
+
+        drop(master);
+        let mut buf = [0; 16];
+        let error = port.read(&mut buf).unwrap_err();
+        assert!(is_disconnected(&error));
+    }
+
+    #[test]
+    fn test_deadline_across_partial_reads() {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let path = ptsname_r(&master).unwrap();
+        let config = SerialConfig::default();
+        let port = TTYPort::open(path, &config, Some(Duration::from_millis(200))).unwrap();
+
+        // Each byte arrives well within the port timeout, but the line is
+        // never terminated
+        let master_fd = master.as_raw_fd();
+        let writer = std::thread::spawn(move || {
+            for _ in 0..20 {
+                let _ = nix::unistd::write(master_fd, b"E");
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut reader = BufReader::new(port);
+        let start = Instant::now();
+        reader
+            .get_mut()
+            .set_deadline(Some(start + Duration::from_millis(300)));
+        let mut line = vec![];
+        let error = reader.read_until(b'\n', &mut line).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_millis(600));
+        assert!(!line.is_empty());
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_find_usb_tty() -> std::io::Result<()> {
+        let root = std::env::temp_dir().join(format!("skstack-sysfs-{}", std::process::id()));
+        let usb = root.join("devices/usb1/1-1");
+        let interface = usb.join("1-1:1.0/ttyUSB1");
+        std::fs::create_dir_all(&interface)?;
+        std::fs::write(usb.join("idVendor"), "0403\n")?;
+        std::fs::write(usb.join("idProduct"), "6015\n")?;
+        std::fs::write(usb.join("serial"), "WSUN0001\n")?;
+        let class_dir = root.join("class/tty");
+        std::fs::create_dir_all(class_dir.join("ttyUSB1"))?;
+        std::fs::create_dir_all(class_dir.join("ttyS0"))?;
+        std::os::unix::fs::symlink(&interface, class_dir.join("ttyUSB1/device"))?;
+
+        let found = find_usb_tty(&class_dir, 0x0403, 0x6015, Some("WSUN0001"));
+        let other_serial = find_usb_tty(&class_dir, 0x0403, 0x6015, Some("WSUN0002"));
+        let other_product = find_usb_tty(&class_dir, 0x0403, 0x6001, None);
+        std::fs::remove_dir_all(&root)?;
+
+        assert_eq!(found?, Some("/dev/ttyUSB1".to_string()));
+        assert_eq!(other_serial?, None);
+        assert_eq!(other_product?, None);
+        Ok(())
+    }
 }